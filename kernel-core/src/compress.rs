@@ -0,0 +1,249 @@
+//! LZ4 block compression, without heap allocation.
+//!
+//! Only the block format is implemented, not the frame format with its header and
+//! checksums, so the caller has to know the compressed and uncompressed sizes.
+
+/// The minimum length of a match.
+const MIN_MATCH: usize = 4;
+/// The last match must start at least this many bytes before the end of the input.
+const MF_LIMIT: usize = 12;
+/// The last bytes of the input are always literals.
+const LAST_LITERALS: usize = 5;
+const MAX_OFFSET: usize = 0xffff;
+
+const HASH_BITS: u32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecompressError {
+    /// The input ended in the middle of a sequence.
+    Truncated,
+    /// The decompressed data does not fit into the output buffer.
+    OutputTooSmall,
+    /// A match refers to data before the start of the output.
+    BadOffset,
+}
+
+/// Returns the largest possible compressed size of `len` input bytes, for sizing the
+/// output buffer of `compress`.
+pub const fn max_compressed_size(len: usize) -> usize {
+    len + len / 255 + 16
+}
+
+/// Compresses `input` into `output` as an LZ4 block and returns the compressed size, or
+/// `None` if `output` is too small.
+///
+/// A greedy single-pass compressor: fast and small rather than thorough.
+pub fn compress(input: &[u8], output: &mut [u8]) -> Option<usize> {
+    let mut out = Output { buf: output, len: 0 };
+    // the position of the last four-byte sequence with each hash, plus one
+    let mut table = [0u32; 1 << HASH_BITS];
+    let mut anchor = 0;
+    let mut pos = 0;
+
+    while pos + MF_LIMIT < input.len() {
+        let sequence = read_u32(input, pos);
+        let hash = hash(sequence);
+        let candidate = table[hash] as usize;
+        table[hash] = pos as u32 + 1;
+
+        if candidate != 0 && pos - (candidate - 1) <= MAX_OFFSET {
+            let start = candidate - 1;
+            if read_u32(input, start) == sequence {
+                let mut len = MIN_MATCH;
+                let end = input.len() - LAST_LITERALS;
+                while pos + len < end && input[start + len] == input[pos + len] {
+                    len += 1;
+                }
+                out.sequence(&input[anchor..pos], Some((pos - start, len)))?;
+                pos += len;
+                anchor = pos;
+                continue;
+            }
+        }
+        pos += 1;
+    }
+
+    out.sequence(&input[anchor..], None)?;
+    Some(out.len)
+}
+
+/// Decompresses the LZ4 block `input` into `output` and returns the decompressed size.
+pub fn decompress(input: &[u8], output: &mut [u8]) -> Result<usize, DecompressError> {
+    let mut i = 0;
+    let mut o = 0;
+
+    loop {
+        let token = *input.get(i).ok_or(DecompressError::Truncated)?;
+        i += 1;
+
+        let mut literals = usize::from(token >> 4);
+        if literals == 15 {
+            literals += read_length(input, &mut i)?;
+        }
+        let literal_end = i.checked_add(literals).ok_or(DecompressError::Truncated)?;
+        if literal_end > input.len() {
+            return Err(DecompressError::Truncated);
+        }
+        if o + literals > output.len() {
+            return Err(DecompressError::OutputTooSmall);
+        }
+        output[o..o + literals].copy_from_slice(&input[i..literal_end]);
+        i = literal_end;
+        o += literals;
+
+        // the last sequence has no match
+        if i == input.len() {
+            return Ok(o);
+        }
+
+        if i + 2 > input.len() {
+            return Err(DecompressError::Truncated);
+        }
+        let offset = usize::from(input[i]) | usize::from(input[i + 1]) << 8;
+        i += 2;
+        if offset == 0 || offset > o {
+            return Err(DecompressError::BadOffset);
+        }
+
+        let mut len = usize::from(token & 0xf);
+        if len == 15 {
+            len += read_length(input, &mut i)?;
+        }
+        len += MIN_MATCH;
+        if o + len > output.len() {
+            return Err(DecompressError::OutputTooSmall);
+        }
+        // byte by byte, since a match may overlap the bytes it produces
+        for _ in 0..len {
+            output[o] = output[o - offset];
+            o += 1;
+        }
+    }
+}
+
+/// Reads the extra bytes of a literal or match length: 255 means another byte follows.
+fn read_length(input: &[u8], i: &mut usize) -> Result<usize, DecompressError> {
+    let mut len = 0usize;
+    loop {
+        let byte = *input.get(*i).ok_or(DecompressError::Truncated)?;
+        *i += 1;
+        len = len.checked_add(usize::from(byte)).ok_or(DecompressError::Truncated)?;
+        if byte != 255 {
+            return Ok(len);
+        }
+    }
+}
+
+fn read_u32(input: &[u8], pos: usize) -> u32 {
+    u32::from(input[pos])
+        | u32::from(input[pos + 1]) << 8
+        | u32::from(input[pos + 2]) << 16
+        | u32::from(input[pos + 3]) << 24
+}
+
+fn hash(sequence: u32) -> usize {
+    // Knuth's multiplicative hash, as in the reference implementation
+    (sequence.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+struct Output<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Output<'a> {
+    fn push(&mut self, byte: u8) -> Option<()> {
+        *self.buf.get_mut(self.len)? = byte;
+        self.len += 1;
+        Some(())
+    }
+
+    fn push_length(&mut self, mut len: usize) -> Option<()> {
+        while len >= 255 {
+            self.push(255)?;
+            len -= 255;
+        }
+        self.push(len as u8)
+    }
+
+    /// Writes `literals` followed by a match of `(offset, length)`, if any.
+    fn sequence(&mut self, literals: &[u8], found: Option<(usize, usize)>) -> Option<()> {
+        let match_len = found.map_or(0, |(_, len)| len - MIN_MATCH);
+        self.push((literals.len().min(15) << 4 | match_len.min(15)) as u8)?;
+        if literals.len() >= 15 {
+            self.push_length(literals.len() - 15)?;
+        }
+        let end = self.len + literals.len();
+        self.buf.get_mut(self.len..end)?.copy_from_slice(literals);
+        self.len = end;
+
+        if let Some((offset, _)) = found {
+            self.push(offset as u8)?;
+            self.push((offset >> 8) as u8)?;
+            if match_len >= 15 {
+                self.push_length(match_len - 15)?;
+            }
+        }
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn round_trip(input: &[u8]) {
+        let mut compressed = vec![0; max_compressed_size(input.len())];
+        let len = compress(input, &mut compressed).unwrap();
+        let mut output = vec![0; input.len()];
+        assert_eq!(decompress(&compressed[..len], &mut output), Ok(input.len()));
+        assert_eq!(&output[..], input);
+    }
+
+    #[test]
+    fn round_trips() {
+        round_trip(b"");
+        round_trip(b"short");
+        round_trip(&[0; 1000]);
+        let text: Vec<u8> = b"the quick brown fox jumps over the lazy dog. "
+            .iter()
+            .cycle()
+            .take(5000)
+            .cloned()
+            .collect();
+        round_trip(&text);
+        let noise: Vec<u8> = (0..3000u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect();
+        round_trip(&noise);
+    }
+
+    #[test]
+    fn repetitive_input_shrinks() {
+        let mut compressed = [0; 64];
+        assert!(compress(&[7; 1000], &mut compressed).is_some());
+    }
+
+    #[test]
+    fn decompress_overlapping_match() {
+        // one literal 'a', then a match of 4 + 5 from offset 1, then the last literals
+        let block = [0x15, b'a', 1, 0, 0x50, b'b', b'c', b'd', b'e', b'f'];
+        let mut output = [0; 16];
+        assert_eq!(decompress(&block, &mut output), Ok(15));
+        assert_eq!(&output[..15], b"aaaaaaaaaabcdef");
+    }
+
+    #[test]
+    fn decompress_rejects_bad_input() {
+        let mut output = [0; 16];
+        assert_eq!(decompress(&[0x10], &mut output), Err(DecompressError::Truncated));
+        assert_eq!(
+            decompress(&[0x10, b'a', 2, 0], &mut output),
+            Err(DecompressError::BadOffset)
+        );
+        assert_eq!(
+            decompress(&[0x10, b'a'], &mut output[..0]),
+            Err(DecompressError::OutputTooSmall)
+        );
+    }
+}
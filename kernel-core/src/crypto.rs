@@ -0,0 +1,256 @@
+//! Hashing and message authentication, without heap allocation.
+
+pub const SHA256_OUTPUT_SIZE: usize = 32;
+const SHA256_BLOCK_SIZE: usize = 64;
+
+const SHA256_INITIAL_STATE: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+const SHA256_ROUND_CONSTANTS: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// An incremental SHA-256 hash.
+#[derive(Clone)]
+pub struct Sha256 {
+    state: [u32; 8],
+    block: [u8; SHA256_BLOCK_SIZE],
+    block_len: usize,
+    /// Bytes hashed so far.
+    len: u64,
+}
+
+impl Sha256 {
+    pub fn new() -> Sha256 {
+        Sha256 {
+            state: SHA256_INITIAL_STATE,
+            block: [0; SHA256_BLOCK_SIZE],
+            block_len: 0,
+            len: 0,
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.len += data.len() as u64;
+        while !data.is_empty() {
+            let count = data.len().min(SHA256_BLOCK_SIZE - self.block_len);
+            self.block[self.block_len..self.block_len + count].copy_from_slice(&data[..count]);
+            self.block_len += count;
+            data = &data[count..];
+            if self.block_len == SHA256_BLOCK_SIZE {
+                let block = self.block;
+                self.compress(&block);
+                self.block_len = 0;
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> [u8; SHA256_OUTPUT_SIZE] {
+        let bit_len = self.len * 8;
+        // a 1 bit, zeros up to 8 bytes before a block boundary, then the length in bits
+        self.update(&[0x80]);
+        while self.block_len != SHA256_BLOCK_SIZE - 8 {
+            self.update(&[0]);
+        }
+        let mut len_bytes = [0; 8];
+        for (i, byte) in len_bytes.iter_mut().enumerate() {
+            *byte = (bit_len >> (56 - 8 * i)) as u8;
+        }
+        self.update(&len_bytes);
+
+        let mut output = [0; SHA256_OUTPUT_SIZE];
+        for (i, word) in self.state.iter().enumerate() {
+            for j in 0..4 {
+                output[4 * i + j] = (word >> (24 - 8 * j)) as u8;
+            }
+        }
+        output
+    }
+
+    fn compress(&mut self, block: &[u8; SHA256_BLOCK_SIZE]) {
+        let mut w = [0u32; 64];
+        for i in 0..16 {
+            w[i] = u32::from(block[4 * i]) << 24
+                | u32::from(block[4 * i + 1]) << 16
+                | u32::from(block[4 * i + 2]) << 8
+                | u32::from(block[4 * i + 3]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let choice = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(choice)
+                .wrapping_add(SHA256_ROUND_CONSTANTS[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let majority = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(majority);
+
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+
+        for (word, value) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h].iter()) {
+            *word = word.wrapping_add(*value);
+        }
+    }
+}
+
+impl Default for Sha256 {
+    fn default() -> Sha256 {
+        Sha256::new()
+    }
+}
+
+/// Hashes `data` in one go.
+pub fn sha256(data: &[u8]) -> [u8; SHA256_OUTPUT_SIZE] {
+    let mut hash = Sha256::new();
+    hash.update(data);
+    hash.finish()
+}
+
+/// An incremental HMAC-SHA256 (RFC 2104).
+#[derive(Clone)]
+pub struct HmacSha256 {
+    inner: Sha256,
+    /// The key padded to a block and XORed with the outer pad.
+    outer_key: [u8; SHA256_BLOCK_SIZE],
+}
+
+impl HmacSha256 {
+    pub fn new(key: &[u8]) -> HmacSha256 {
+        // keys longer than a block are hashed first, shorter ones padded with zeros
+        let mut block_key = [0; SHA256_BLOCK_SIZE];
+        if key.len() > SHA256_BLOCK_SIZE {
+            block_key[..SHA256_OUTPUT_SIZE].copy_from_slice(&sha256(key));
+        } else {
+            block_key[..key.len()].copy_from_slice(key);
+        }
+
+        let mut inner_key = block_key;
+        let mut outer_key = block_key;
+        for byte in inner_key.iter_mut() {
+            *byte ^= 0x36;
+        }
+        for byte in outer_key.iter_mut() {
+            *byte ^= 0x5c;
+        }
+
+        let mut inner = Sha256::new();
+        inner.update(&inner_key);
+        HmacSha256 { inner, outer_key }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.inner.update(data);
+    }
+
+    pub fn finish(self) -> [u8; SHA256_OUTPUT_SIZE] {
+        let mut outer = Sha256::new();
+        outer.update(&self.outer_key);
+        outer.update(&self.inner.finish());
+        outer.finish()
+    }
+}
+
+/// Computes the HMAC-SHA256 of `data` under `key` in one go.
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; SHA256_OUTPUT_SIZE] {
+    let mut mac = HmacSha256::new(key);
+    mac.update(data);
+    mac.finish()
+}
+
+/// Compares `a` and `b` in time that depends only on their lengths, not on where they
+/// differ. Use it for MACs and hashes, so a mismatch position can't be timed.
+pub fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let difference = a.iter().zip(b).fold(0, |difference, (x, y)| difference | (x ^ y));
+    // volatile, so the compiler can't turn the fold into an early exit
+    unsafe { core::ptr::read_volatile(&difference) == 0 }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn sha256_known_answers() {
+        assert_eq!(
+            hex(&sha256(b"")),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            hex(&sha256(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            hex(&sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+
+    #[test]
+    fn sha256_incremental_matches_one_shot() {
+        let data = [0x5a; 200];
+        let mut hash = Sha256::new();
+        for chunk in data.chunks(7) {
+            hash.update(chunk);
+        }
+        assert_eq!(hash.finish(), sha256(&data));
+    }
+
+    #[test]
+    fn hmac_sha256_rfc_4231() {
+        // test case 2
+        assert_eq!(
+            hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        // test case 6, a key longer than a block
+        assert_eq!(
+            hex(&hmac_sha256(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[test]
+    fn ct_eq_compares_contents_and_length() {
+        assert!(ct_eq(b"secret", b"secret"));
+        assert!(!ct_eq(b"secret", b"secreT"));
+        assert!(!ct_eq(b"secret", b"secret!"));
+    }
+}
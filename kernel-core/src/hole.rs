@@ -0,0 +1,513 @@
+use core::alloc::{AllocErr, Layout};
+use core::ptr::NonNull;
+use core::mem::{align_of, size_of};
+
+
+/// Writes the header of a new hole into the memory it describes, see `HoleList::set_store`.
+pub type StoreFn = unsafe fn(*mut Hole, Hole);
+
+pub struct HoleList {
+    head: Hole,
+    len: usize,
+    store: Option<StoreFn>,
+    /// Address of the hole before the one the last allocation came from, 0 for the head.
+    /// The next search starts there.
+    #[cfg(feature = "next_fit")]
+    cursor: usize,
+}
+
+impl HoleList {
+    /// Creates an empty `HoleList`.
+    pub const fn empty() -> HoleList {
+        HoleList {
+            head: Hole {
+                size: 0,
+                next: None,
+            },
+            len: 0,
+            store: None,
+            #[cfg(feature = "next_fit")]
+            cursor: 0,
+        }
+    }
+
+    /// Create new hole for empty
+    /// # Unsafe
+    /// The call will be invalide if `[hole_addr, hole_addr+size) range is used somewhere else.
+    pub unsafe fn new(hole_addr: usize, hole_size: usize) -> HoleList {
+        assert_eq!(size_of::<Hole>(), Self::min_size());
+
+        let ptr = hole_addr as *mut Hole;
+        ptr.write(Hole {
+            size: hole_size,
+            next: None,
+        });
+
+        HoleList {
+            head: Hole {
+                size: 0,
+                next: Some(&mut *ptr),
+            },
+            len: 1,
+            store: None,
+            #[cfg(feature = "next_fit")]
+            cursor: 0,
+        }
+    }
+
+    pub fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
+        assert!(layout.size() >= Self::min_size());
+
+        let (allocation, previous) = self.find_fit(layout)?;
+        self.len -= 1;
+        #[cfg(feature = "next_fit")]
+        {
+            self.cursor = previous;
+        }
+        #[cfg(not(feature = "next_fit"))]
+        let _ = previous;
+
+        if let Some(front_hole_info) = allocation.front_hole_info {
+            self.free(front_hole_info.addr, front_hole_info.size);
+        }
+        if let Some(back_hole_info) = allocation.back_hole_info {
+            self.free(back_hole_info.addr, back_hole_info.size);
+        }
+        Ok(NonNull::new(allocation.allocated_info.addr as *mut u8).unwrap())
+    }
+
+    /// Adds the free memory `[addr, addr + size)` to the list. It need not be adjacent to
+    /// memory the list already manages, and is merged with any hole it touches.
+    ///
+    /// # Unsafe
+    /// The range must be unused and not overlap memory the list already manages.
+    pub unsafe fn extend(&mut self, addr: usize, size: usize) {
+        let start = align_up(addr, align_of::<Hole>());
+        let end = align_down(addr + size, align_of::<Hole>());
+        assert!(end >= start + Self::min_size(), "region too small for a hole");
+
+        self.free(start, end - start);
+    }
+
+    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        self.free(ptr.as_ptr() as usize, layout.size())
+    }
+
+    /// Takes the first hole that fits, searching from the head.
+    #[cfg(not(feature = "next_fit"))]
+    fn find_fit(&mut self, layout: Layout) -> Result<(AllocInfo, usize), AllocErr> {
+        allocate_first_fit(&mut self.head, layout, 0)
+    }
+
+    /// Takes the first hole that fits, searching from the cursor to the end of the list and
+    /// then from the head up to the cursor. Skips the fragmented front of the heap that
+    /// first fit would walk every time.
+    #[cfg(feature = "next_fit")]
+    fn find_fit(&mut self, layout: Layout) -> Result<(AllocInfo, usize), AllocErr> {
+        if self.cursor != 0 {
+            // `free` resets the cursor when its hole leaves the list
+            let cursor = unsafe { &mut *(self.cursor as *mut Hole) };
+            if let Ok(found) = allocate_first_fit(cursor, layout, 0) {
+                return Ok(found);
+            }
+        }
+        allocate_first_fit(&mut self.head, layout, self.cursor)
+    }
+
+    fn free(&mut self, addr: usize, size: usize) {
+        // a hole that a freed block ends at is merged into the block and leaves the list
+        #[cfg(feature = "next_fit")]
+        {
+            if self.cursor == addr + size {
+                self.cursor = 0;
+            }
+        }
+        let delta = deallocate(&mut self.head, addr, size, self.store);
+        self.len = (self.len as isize + delta) as usize;
+    }
+
+    /// Makes the list write the headers of new holes through `store` rather than
+    /// `ptr::write`, so the kernel can check them against its shadow memory. `None` goes
+    /// back to `ptr::write`.
+    pub fn set_store(&mut self, store: Option<StoreFn>) {
+        self.store = store;
+    }
+
+    /// Returns the number of holes in the list, a rough measure of fragmentation.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether the list has no holes, i.e. all of its memory is allocated.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the minimal allocation size. Smaller allocations or deallocations are not allowed.
+    pub fn min_size() -> usize {
+        size_of::<usize>() * 2
+    }
+
+    /// Returns the `(address, size)` of every hole, in address order.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        Iter {
+            next: self.head.next.as_deref(),
+        }
+    }
+
+}
+
+struct Iter<'a> {
+    next: Option<&'a Hole>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (usize, usize);
+
+    fn next(&mut self) -> Option<(usize, usize)> {
+        let hole = self.next?;
+        self.next = hole.next.as_deref();
+        Some((hole as *const Hole as usize, hole.size))
+    }
+}
+
+pub struct Hole {
+    size: usize,
+    next: Option<&'static mut Hole>,
+}
+
+impl Hole {
+    /// Returns basic information about the hole.
+    fn info(&self) -> HoleInfo {
+        HoleInfo {
+            addr: self as *const _ as usize,
+            size: self.size,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct HoleInfo {
+    addr: usize,
+    size: usize,
+}
+
+/// Contains the address and size of the allocation (in the `info` field),
+/// and the front and back padding.
+struct AllocInfo {
+    allocated_info: HoleInfo,
+    front_hole_info: Option<HoleInfo>,
+    back_hole_info: Option<HoleInfo>,
+}
+
+// Search for the first fit hole after `previous`, giving up once the hole at `stop` was
+// checked (0 searches to the end). Also returns the address of the hole before the one
+// allocated from, 0 for the head.
+fn allocate_first_fit(
+    mut previous: &mut Hole,
+    layout: Layout,
+    stop: usize,
+) -> Result<(AllocInfo, usize), AllocErr> {
+    loop {
+        let alloc_info: Option<AllocInfo> = previous
+            .next
+            .as_mut()
+            .and_then(|current| split_hole(current.info(), layout));
+        match alloc_info {
+            Some(alloc_info) => {
+                // hole is big enough, so remove it from the list by updating the previous pointer
+                let previous_addr = hole_addr(previous);
+                previous.next = previous.next.as_mut().unwrap().next.take();
+                return Ok((alloc_info, previous_addr));
+            }
+            None if previous.next.is_some() => {
+                // try next hole
+                previous = previous.next.as_mut().unwrap();
+                if hole_addr(previous) == stop {
+                    return Err(AllocErr);
+                }
+            }
+            None => {
+                // this was the last hole, so no hole is big enough -> Allocation not possible
+                return Err(AllocErr);
+            }
+        }
+    }
+}
+
+/// Returns the address of `hole`, or 0 for the dummy hole at the head of the list.
+fn hole_addr(hole: &Hole) -> usize {
+    if hole.size == 0 {
+        0
+    } else {
+        hole as *const Hole as usize
+    }
+}
+
+/// Splits the given hole into `(front_padding, hole, back_padding)`. None will returned if
+/// size is bigger than hole
+fn split_hole(hole: HoleInfo, layout: Layout) -> Option<AllocInfo> {
+    if layout.size() > hole.size {
+        return None;
+    }
+
+    // Work with alignment to decide front hole
+    let (aligned_addr, front_hole) = if hole.addr == align_up(hole.addr, layout.align()) {
+        // hole has already the required alignment
+        (hole.addr, None)
+    } else {
+        // the required alignment causes some padding before the allocation
+        let aligned_addr = align_up(hole.addr + HoleList::min_size(), layout.align());
+        (
+            aligned_addr,
+            Some(HoleInfo {
+                addr: hole.addr,
+                size: aligned_addr - hole.addr,
+            }),
+        )
+    };
+
+    if aligned_addr - hole.addr > hole.size - layout.size() {
+        // hole is too small after alignment
+        return None;
+    }
+
+    // Work with allocation information
+    let allocated_hole = {
+        HoleInfo {
+            addr: aligned_addr,
+            size: hole.size - (aligned_addr - hole.addr),
+        }
+    };
+
+    let back_hole = if allocated_hole.size - layout.size() < HoleList::min_size() {
+        None
+    } else {
+        // the hole is bigger than necessary, so there is some padding behind the allocation
+        Some(HoleInfo {
+            addr: allocated_hole.addr + layout.size(),
+            size: allocated_hole.size - layout.size(),
+        })
+    };
+
+    Some(AllocInfo {
+        allocated_info: HoleInfo {
+            addr: allocated_hole.addr,
+            size: layout.size(),
+        },
+        front_hole_info: front_hole,
+        back_hole_info: back_hole,
+    })
+}
+
+
+
+
+/// refer https://os.phil-opp.com/kernel-heap/#alignment
+pub fn align_down(addr: usize, align: usize) -> usize {
+    if align.is_power_of_two() {
+        addr & !(align - 1)
+    } else if align == 0 {
+        addr
+    } else {
+        panic!("`align` must be a power of 2");
+    }
+}
+
+pub fn align_up(addr: usize, align: usize) -> usize {
+    align_down(addr + align - 1, align)
+}
+
+/// Frees the allocation given by `(addr, size)`.
+/// Returns how much the number of holes changed.
+fn deallocate(
+    mut hole: &mut Hole,
+    addr: usize,
+    mut size: usize,
+    store: Option<StoreFn>,
+) -> isize {
+    let mut delta = 0;
+    loop {
+        assert!(size >= HoleList::min_size());
+
+        let hole_addr = if hole.size == 0 {
+            // It's the dummy hole, which is the head of the HoleList.
+            0
+        } else {
+            hole as *mut _ as usize
+        };
+
+        // Each freed block must be handled by the previous hole in memory. Thus the freed
+        // address must be always behind the current hole.
+        assert!(
+            hole_addr <= addr - hole.size,
+            "invalid deallocation (probably a double free)"
+        );
+
+        // get information about the next block
+        let next_hole_info = hole.next.as_ref().map(|next| next.info());
+
+        match next_hole_info {
+            Some(next) if hole_addr + hole.size == addr && addr + size == next.addr => {
+                // block fills the gap between two holes, merge the three block
+                // remove the second hole
+                hole.size += size + next.size;
+                hole.next = hole.next.as_mut().unwrap().next.take();
+                delta -= 1;
+            }
+            _ if hole_addr + hole.size == addr => {
+                // block concatenate with the hole before
+                hole.size += size;
+            }
+            Some(next) if addr == next.addr - size => {
+                // block concatenate with the hole after
+                hole.next = hole.next.as_mut().unwrap().next.take();
+                size += next.size;
+                delta -= 1;
+                continue;
+            }
+            Some(next) if next.addr <= addr => {
+                // block after the hole after
+                hole = move_helper(hole).next.as_mut().unwrap();
+                continue;
+            }
+            _ => {
+                // block between the two hole and does not concatenate
+                let new_hole = Hole {
+                    size: size,
+                    next: hole.next.take(),
+                };
+                // write the new hole to the freed memory
+                let ptr = addr as *mut Hole;
+                match store {
+                    Some(store) => unsafe { store(ptr, new_hole) },
+                    None => unsafe { ptr.write(new_hole) },
+                }
+                // add the F block as the next block of the X block
+                hole.next = Some(unsafe { &mut *ptr });
+                delta += 1;
+            }
+        }
+        break;
+    }
+    delta
+}
+
+fn move_helper<T>(x: T) -> T {
+    x
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    fn holes(size: usize) -> HoleList {
+        let memory = Box::leak(vec![0usize; size / size_of::<usize>()].into_boxed_slice());
+        unsafe { HoleList::new(memory.as_mut_ptr() as usize, size) }
+    }
+
+    #[test]
+    fn freed_neighbours_merge() {
+        let mut holes = holes(1024);
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let a = holes.alloc(layout).unwrap();
+        let b = holes.alloc(layout).unwrap();
+        assert_eq!(holes.len(), 1);
+        unsafe { holes.deallocate(a, layout) };
+        assert_eq!(holes.len(), 2);
+        unsafe { holes.deallocate(b, layout) };
+        assert_eq!(holes.len(), 1);
+        assert_eq!(holes.iter().next().map(|(_, size)| size), Some(1024));
+    }
+
+    #[test]
+    fn iter_walks_every_hole() {
+        let mut holes = holes(1024);
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let allocations: Vec<_> = (0..4).map(|_| holes.alloc(layout).unwrap()).collect();
+        // free every other block, so none of the holes touch
+        unsafe {
+            holes.deallocate(allocations[0], layout);
+            holes.deallocate(allocations[2], layout);
+        }
+        let base = allocations[0].as_ptr() as usize;
+        let found: Vec<_> = holes.iter().map(|(addr, size)| (addr - base, size)).collect();
+        assert_eq!(found, [(0, 64), (128, 64), (256, 1024 - 256)]);
+        assert_eq!(found.len(), holes.len());
+    }
+
+    static STORES: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe fn counting_store(ptr: *mut Hole, hole: Hole) {
+        STORES.fetch_add(1, Ordering::SeqCst);
+        ptr.write(hole);
+    }
+
+    #[test]
+    fn new_holes_are_written_through_the_store() {
+        let mut holes = holes(1024);
+        holes.set_store(Some(counting_store));
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let allocations: Vec<_> = (0..3).map(|_| holes.alloc(layout).unwrap()).collect();
+        let stores = STORES.load(Ordering::SeqCst);
+        // not next to the remaining hole, so it gets a header of its own
+        unsafe { holes.deallocate(allocations[0], layout) };
+        assert_eq!(STORES.load(Ordering::SeqCst), stores + 1);
+        assert_eq!(holes.len(), 2);
+    }
+
+    #[test]
+    fn allocations_are_aligned() {
+        let mut holes = holes(4096);
+        let layout = Layout::from_size_align(32, 256).unwrap();
+        for _ in 0..4 {
+            assert_eq!(holes.alloc(layout).unwrap().as_ptr() as usize % 256, 0);
+        }
+    }
+
+    /// Leaves holes of 64 bytes at offsets 0 and 128, then allocates 128 bytes from the
+    /// tail. Returns the offset of the next 64 byte allocation.
+    fn small_allocation_after_large_one() -> usize {
+        let mut holes = holes(1024);
+        let small = Layout::from_size_align(64, 8).unwrap();
+        let allocations: Vec<_> = (0..4).map(|_| holes.alloc(small).unwrap()).collect();
+        unsafe {
+            holes.deallocate(allocations[0], small);
+            holes.deallocate(allocations[2], small);
+        }
+        let large = holes.alloc(Layout::from_size_align(128, 8).unwrap()).unwrap();
+        let base = allocations[0].as_ptr() as usize;
+        assert_eq!(large.as_ptr() as usize - base, 256);
+        holes.alloc(small).unwrap().as_ptr() as usize - base
+    }
+
+    #[cfg(not(feature = "next_fit"))]
+    #[test]
+    fn first_fit_starts_from_the_head() {
+        assert_eq!(small_allocation_after_large_one(), 0);
+    }
+
+    #[cfg(feature = "next_fit")]
+    #[test]
+    fn next_fit_resumes_after_the_last_allocation() {
+        assert_eq!(small_allocation_after_large_one(), 384);
+    }
+
+    #[test]
+    fn allocating_everything_empties_the_list() {
+        let mut holes = holes(256);
+        let layout = Layout::from_size_align(256, 8).unwrap();
+        let all = holes.alloc(layout).unwrap();
+        assert!(holes.is_empty());
+        unsafe { holes.deallocate(all, layout) };
+        assert!(!holes.is_empty());
+    }
+
+    #[test]
+    fn oversized_allocations_fail() {
+        let mut holes = holes(256);
+        assert!(holes.alloc(Layout::from_size_align(512, 8).unwrap()).is_err());
+        assert_eq!(holes.len(), 1);
+    }
+}
@@ -0,0 +1,11 @@
+//! The parts of the kernel that are plain logic, without `x86_64`, `bootloader` or any
+//! hardware access, so they build for the host and `cargo test -p kernel-core` runs their
+//! unit tests there. `os_rust` re-exports the modules under their old paths.
+
+#![cfg_attr(not(test), no_std)]
+#![feature(allocator_api)]
+#![feature(const_fn)]
+
+pub mod compress;
+pub mod crypto;
+pub mod hole;
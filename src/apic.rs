@@ -0,0 +1,100 @@
+use crate::memory::{self, MapFlags};
+use crate::msr::ApicBase;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::PhysAddr;
+
+// register offsets from the APIC base
+const ID: usize = 0x20;
+const EOI: usize = 0xb0;
+const SPURIOUS: usize = 0xf0;
+const ICR_LOW: usize = 0x300;
+const ICR_HIGH: usize = 0x310;
+
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_ALL_BUT_SELF: u32 = 0b11 << 18;
+
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// Virtual (identity mapped) address of the local APIC registers, 0 until `init`.
+static BASE: AtomicUsize = AtomicUsize::new(0);
+
+/// Where an inter-processor interrupt is delivered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpiDestination {
+    /// The CPU with the given local APIC id.
+    Cpu(u8),
+    /// Every CPU except the sending one.
+    AllButSelf,
+}
+
+/// Reserves and identity maps the local APIC registers and software-enables the APIC.
+/// Must run after `memory::init_kernel_pages`.
+///
+/// The legacy PICs keep working, since the firmware leaves LINT0 in ExtINT mode.
+pub fn init() {
+    use x86_64::structures::paging::PageTableFlags as Flags;
+
+    let base = ApicBase::read().address.as_u64();
+    let frame = PhysFrame::containing_address(PhysAddr::new(base));
+    memory::reserve(frame.start_address()..frame.start_address() + 4096u64, "apic");
+    let flags = Flags::WRITABLE | Flags::NO_EXECUTE | MapFlags::Uncached.page_table_flags();
+
+    if let Err(err) = memory::identity_map(frame, flags) {
+        panic!("failed to map the local APIC: {:?}", err);
+    }
+    BASE.store(base as usize, Ordering::SeqCst);
+
+    unsafe { write(SPURIOUS, 0x100 | u32::from(SPURIOUS_VECTOR)) };
+}
+
+/// Returns whether `init` has run.
+pub fn is_enabled() -> bool {
+    BASE.load(Ordering::SeqCst) != 0
+}
+
+/// Returns the local APIC id of the current CPU.
+pub fn id() -> u8 {
+    if !is_enabled() {
+        return 0;
+    }
+    (unsafe { read(ID) } >> 24) as u8
+}
+
+/// Signals the end of an interrupt delivered through the local APIC.
+pub fn end_of_interrupt() {
+    if is_enabled() {
+        unsafe { write(EOI, 0) };
+    }
+}
+
+/// Sends a fixed inter-processor interrupt and waits until the APIC accepted it.
+/// Does nothing before `init`.
+pub fn send_ipi(destination: IpiDestination, vector: u8) {
+    if !is_enabled() {
+        return;
+    }
+
+    let (high, shorthand) = match destination {
+        IpiDestination::Cpu(id) => (u32::from(id) << 24, 0),
+        IpiDestination::AllButSelf => (0, ICR_ALL_BUT_SELF),
+    };
+
+    // the two ICR writes must not be interleaved with another IPI from an interrupt handler
+    interrupts::without_interrupts(|| unsafe {
+        write(ICR_HIGH, high);
+        write(ICR_LOW, shorthand | ICR_LEVEL_ASSERT | u32::from(vector));
+        while read(ICR_LOW) & ICR_DELIVERY_PENDING != 0 {}
+    });
+}
+
+unsafe fn read(register: usize) -> u32 {
+    ptr::read_volatile((BASE.load(Ordering::SeqCst) + register) as *const u32)
+}
+
+unsafe fn write(register: usize, value: u32) {
+    ptr::write_volatile((BASE.load(Ordering::SeqCst) + register) as *mut u32, value)
+}
@@ -0,0 +1,31 @@
+/// Upper bound on the frames visited, in case the frame pointer chain is corrupted.
+const MAX_FRAMES: usize = 16;
+
+/// Calls `f` with the return address of each frame on the current stack, innermost first.
+///
+/// This follows the saved `rbp` chain, so it relies on the target spec keeping frame
+/// pointers. The walk stops at a null, misaligned, or non-increasing frame pointer.
+pub fn walk<F: FnMut(usize)>(mut f: F) {
+    let mut rbp: usize;
+    unsafe { asm!("mov %rbp, $0" : "=r"(rbp) ::: "volatile") };
+
+    for _ in 0..MAX_FRAMES {
+        if rbp == 0 || rbp % 8 != 0 {
+            break;
+        }
+
+        // [rbp] holds the caller's rbp, [rbp + 8] the return address into the caller
+        let frame = rbp as *const usize;
+        let return_addr = unsafe { *frame.offset(1) };
+        if return_addr == 0 {
+            break;
+        }
+        f(return_addr);
+
+        let next = unsafe { *frame };
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+}
@@ -10,6 +10,7 @@ use core::panic::PanicInfo;
 #[cfg(not(test))]
 #[no_mangle] // don't mangle the name of this function
 pub extern "C" fn _start() -> ! {
+    os_rust::panic::enter_test_mode();
     serial_println!("ok");
 
     unsafe {
@@ -22,12 +23,5 @@ pub extern "C" fn _start() -> ! {
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    serial_println!("failed");
-
-    serial_println!("{}", info);
-
-    unsafe {
-        exit_qemu();
-    }
-    loop {}
+    os_rust::panic::handle(info)
 }
@@ -8,6 +8,7 @@ use core::panic::PanicInfo;
 #[cfg(not(test))]
 #[no_mangle]
 pub extern "C" fn _start() -> ! {
+    os_rust::panic::enter_test_mode();
     os_rust::interrupts::init_idt();
 
     x86_64::instructions::int3();
@@ -23,12 +24,5 @@ pub extern "C" fn _start() -> ! {
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    serial_println!("failed");
-
-    serial_println!("{}", info);
-
-    unsafe {
-        exit_qemu();
-    }
-    loop {}
+    os_rust::panic::handle(info)
 }
@@ -12,6 +12,7 @@ use x86_64::structures::idt::{ExceptionStackFrame, InterruptDescriptorTable};
 #[no_mangle]
 #[allow(unconditional_recursion)]
 pub extern "C" fn _start() -> ! {
+    os_rust::panic::enter_test_mode();
     os_rust::gdt::init();
     init_test_idt();
 
@@ -33,14 +34,7 @@ pub extern "C" fn _start() -> ! {
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    serial_println!("failed");
-
-    serial_println!("{}", info);
-
-    unsafe {
-        exit_qemu();
-    }
-    loop {}
+    os_rust::panic::handle(info)
 }
 
 lazy_static! {
@@ -0,0 +1,108 @@
+#![feature(abi_x86_interrupt)]
+#![feature(alloc)]
+#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(test, allow(dead_code, unused_macros, unused_imports))]
+
+#[macro_use]
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use os_rust::interrupts::{PICS, KEYBOARD_INTERRUPT_ID, TIMER_INTERRUPT_ID};
+use os_rust::{block_alloc, exit_qemu, serial_println, HEAP_ALLOCATOR};
+use x86_64::structures::idt::{ExceptionStackFrame, InterruptDescriptorTable};
+
+const HEAP_SIZE: usize = 64 * 1024;
+/// Timer interrupts that must allocate while the heap is being hammered.
+const TIMER_ALLOCATIONS: usize = 200;
+const MAX_ROUNDS: usize = 1_000_000;
+
+#[repr(align(4096))]
+struct Heap([u8; HEAP_SIZE]);
+
+static mut HEAP: Heap = Heap([0; HEAP_SIZE]);
+
+static IRQ_ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static IRQ_CORRUPTION: AtomicBool = AtomicBool::new(false);
+
+#[cfg(not(test))]
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    os_rust::panic::enter_test_mode();
+    os_rust::gdt::init();
+    init_test_idt();
+    unsafe {
+        HEAP_ALLOCATOR.lock().init(&HEAP as *const Heap as usize, HEAP_SIZE);
+        PICS.lock().initialize();
+    }
+    os_rust::pit::set_frequency(1000);
+    x86_64::instructions::interrupts::enable();
+
+    let mut rounds = 0;
+    while IRQ_ALLOCATIONS.load(Ordering::SeqCst) < TIMER_ALLOCATIONS {
+        assert!(rounds < MAX_ROUNDS, "timer allocations did not happen");
+        let fill = rounds as u8;
+        let buffer: Vec<u8> = vec![fill; 1 + rounds % 300];
+        assert!(buffer.iter().all(|&byte| byte == fill), "heap buffer corrupted");
+        rounds += 1;
+    }
+
+    x86_64::instructions::interrupts::disable();
+    assert!(!IRQ_CORRUPTION.load(Ordering::SeqCst), "pool block corrupted");
+    assert_eq!(block_alloc::free_blocks(), 64, "pool block leaked");
+
+    serial_println!("ok");
+
+    unsafe {
+        exit_qemu();
+    }
+    loop {}
+}
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os_rust::panic::handle(info)
+}
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt[usize::from(TIMER_INTERRUPT_ID)].set_handler_fn(timer_interrupt_handler);
+        idt[usize::from(KEYBOARD_INTERRUPT_ID)].set_handler_fn(keyboard_interrupt_handler);
+        idt
+    };
+}
+
+pub fn init_test_idt() {
+    TEST_IDT.load();
+}
+
+extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: &mut ExceptionStackFrame) {
+    let count = IRQ_ALLOCATIONS.load(Ordering::SeqCst);
+    if let Some(block) = block_alloc::alloc() {
+        let fill = count as u8;
+        let bytes = unsafe { &mut *block.as_ptr() };
+        for byte in bytes.iter_mut() {
+            *byte = fill;
+        }
+        if bytes.iter().any(|&byte| byte != fill) {
+            IRQ_CORRUPTION.store(true, Ordering::SeqCst);
+        }
+        unsafe { block_alloc::free(block) };
+        IRQ_ALLOCATIONS.store(count + 1, Ordering::SeqCst);
+    }
+
+    unsafe { PICS.lock().notify_end_of_interrupt(TIMER_INTERRUPT_ID) }
+}
+
+extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut ExceptionStackFrame) {
+    let port = os_rust::keyboard::data_port();
+    unsafe {
+        port.read();
+        PICS.lock().notify_end_of_interrupt(KEYBOARD_INTERRUPT_ID)
+    }
+}
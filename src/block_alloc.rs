@@ -0,0 +1,59 @@
+use core::cell::UnsafeCell;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+pub const BLOCK_SIZE: usize = 64;
+/// One bit of `FREE` per block.
+const BLOCK_COUNT: usize = 64;
+
+#[repr(align(64))]
+struct Pool(UnsafeCell<[[u8; BLOCK_SIZE]; BLOCK_COUNT]>);
+
+// blocks are handed out to one owner at a time through `FREE`
+unsafe impl Sync for Pool {}
+
+static POOL: Pool = Pool(UnsafeCell::new([[0; BLOCK_SIZE]; BLOCK_COUNT]));
+/// Bit `i` is set while block `i` is free.
+static FREE: AtomicUsize = AtomicUsize::new(!0);
+
+/// A fixed pool of small blocks that is safe to use from interrupt handlers.
+///
+/// The global heap is behind a spin lock, so an interrupt handler allocating from it while
+/// the interrupted code holds the lock deadlocks. This pool takes no lock, claiming blocks
+/// with a compare-and-swap on a bitmap instead.
+pub fn alloc() -> Option<NonNull<[u8; BLOCK_SIZE]>> {
+    let mut free = FREE.load(Ordering::Acquire);
+    loop {
+        if free == 0 {
+            return None;
+        }
+        let index = free.trailing_zeros() as usize;
+        let taken = free & !(1 << index);
+        match FREE.compare_exchange_weak(free, taken, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => {
+                let blocks = POOL.0.get() as *mut [u8; BLOCK_SIZE];
+                return NonNull::new(unsafe { blocks.add(index) });
+            }
+            Err(current) => free = current,
+        }
+    }
+}
+
+/// Returns a block to the pool.
+///
+/// # Unsafe
+/// `block` must come from `alloc` and not be used afterwards.
+pub unsafe fn free(block: NonNull<[u8; BLOCK_SIZE]>) {
+    let blocks = POOL.0.get() as usize;
+    let offset = block.as_ptr() as usize - blocks;
+    assert!(offset % BLOCK_SIZE == 0 && offset / BLOCK_SIZE < BLOCK_COUNT, "not a pool block");
+
+    let bit = 1 << (offset / BLOCK_SIZE);
+    let previous = FREE.fetch_or(bit, Ordering::AcqRel);
+    assert!(previous & bit == 0, "double free of pool block");
+}
+
+/// Returns the number of free blocks.
+pub fn free_blocks() -> usize {
+    FREE.load(Ordering::Relaxed).count_ones() as usize
+}
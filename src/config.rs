@@ -0,0 +1,132 @@
+use crate::large_alloc::PAGE_SIZE;
+use crate::println;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// A tunable setting with a default and an allowed range.
+pub struct Knob {
+    name: &'static str,
+    default: usize,
+    min: usize,
+    max: usize,
+    value: AtomicUsize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    UnknownKnob,
+    /// The value is outside `[min, max]`.
+    OutOfRange { min: usize, max: usize },
+    /// A `name=value` pair was malformed or the value not a number.
+    InvalidValue,
+}
+
+impl Knob {
+    pub const fn new(name: &'static str, default: usize, min: usize, max: usize) -> Knob {
+        Knob {
+            name,
+            default,
+            min,
+            max,
+            value: AtomicUsize::new(default),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn get(&self) -> usize {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, value: usize) -> Result<(), ConfigError> {
+        if value < self.min || value > self.max {
+            return Err(ConfigError::OutOfRange {
+                min: self.min,
+                max: self.max,
+            });
+        }
+        self.value.store(value, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn reset(&self) {
+        self.value.store(self.default, Ordering::Relaxed);
+    }
+}
+
+/// Allocations above this many bytes get whole pages instead of a hole list block.
+pub static HEAP_LARGE_THRESHOLD: Knob =
+    Knob::new("heap.large_threshold", PAGE_SIZE, PAGE_SIZE, usize::max_value());
+/// Bytes the heap grows by at least when it runs out, see `HeapAllocator::set_grow`.
+pub static HEAP_GROWTH_STEP: Knob =
+    Knob::new("heap.growth_step", 64 * 1024, PAGE_SIZE, usize::max_value());
+/// Whether `kernel_main` checks the interrupt routing at boot, 0 or 1.
+pub static IRQ_SELF_TEST: Knob = Knob::new("irq.self_test", 1, 0, 1);
+/// Whether the console echoes typed lines in canonical mode, 0 or 1.
+pub static CONSOLE_ECHO: Knob = Knob::new("console.echo", 1, 0, 1);
+
+/// Whether panics write a crash dump to the serial port: 0 no, 1 yes, 2 LZ4 compressed.
+pub static PANIC_CRASH_DUMP: Knob = Knob::new("panic.crash_dump", 0, 0, 2);
+/// Allocations above this many bytes are traced, see `trace::alloc`.
+pub static TRACE_ALLOC_THRESHOLD: Knob =
+    Knob::new("trace.alloc_threshold", PAGE_SIZE, 0, usize::max_value());
+
+/// Whether randomized decisions use `boot.seed` instead of a fresh seed, 0 or 1, for
+/// replaying a boot. See `cpu::boot_seed`.
+pub static DETERMINISTIC: Knob = Knob::new("boot.deterministic", 0, 0, 1);
+/// The seed used with `boot.deterministic`.
+pub static BOOT_SEED: Knob = Knob::new("boot.seed", 0, 0, usize::max_value());
+
+static KNOBS: [&Knob; 8] = [
+    &HEAP_LARGE_THRESHOLD,
+    &HEAP_GROWTH_STEP,
+    &IRQ_SELF_TEST,
+    &CONSOLE_ECHO,
+    &PANIC_CRASH_DUMP,
+    &TRACE_ALLOC_THRESHOLD,
+    &DETERMINISTIC,
+    &BOOT_SEED,
+];
+
+/// Returns the knob called `name`.
+pub fn find(name: &str) -> Option<&'static Knob> {
+    KNOBS.iter().map(|&knob| knob).find(|knob| knob.name == name)
+}
+
+pub fn get(name: &str) -> Option<usize> {
+    find(name).map(Knob::get)
+}
+
+pub fn set(name: &str, value: usize) -> Result<(), ConfigError> {
+    find(name).ok_or(ConfigError::UnknownKnob)?.set(value)
+}
+
+/// Returns the kernel command line. The bootloader has none to pass on, so it is baked in at
+/// build time from `OS_RUST_ARGS`, e.g. `OS_RUST_ARGS="irq.self_test=0" bootimage run`.
+pub fn cmdline() -> &'static str {
+    option_env!("OS_RUST_ARGS").unwrap_or("")
+}
+
+/// Applies space separated `name=value` pairs, as found on a kernel command line.
+///
+/// Stops at the first bad pair, leaving the pairs before it applied.
+pub fn apply_args(args: &str) -> Result<(), ConfigError> {
+    for arg in args.split_whitespace() {
+        let mut parts = arg.splitn(2, '=');
+        let name = parts.next().ok_or(ConfigError::InvalidValue)?;
+        let value = parts
+            .next()
+            .and_then(|value| value.parse().ok())
+            .ok_or(ConfigError::InvalidValue)?;
+        set(name, value)?;
+    }
+    Ok(())
+}
+
+/// Prints every knob with its value and default.
+pub fn dump() {
+    for knob in KNOBS.iter() {
+        println!("{} = {} (default {})", knob.name, knob.get(), knob.default);
+    }
+}
@@ -0,0 +1,183 @@
+use crate::input::{self, InputEvent, SubscriberId};
+use crate::{config, print, vga_buffer};
+use core::sync::atomic::{AtomicBool, Ordering};
+use pc_keyboard::DecodedKey;
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+
+const LINE_MAX: usize = 256;
+const QUEUE_SIZE: usize = 1024;
+
+const BACKSPACE: char = '\u{8}';
+const CTRL_C: char = '\u{3}';
+
+/// How key presses reach readers of the console.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Keys are echoed and collected into a line that can be edited with backspace.
+    /// Readers only see whole lines. Ctrl+C discards the line.
+    Canonical,
+    /// Keys are passed to readers as soon as they arrive, without echo.
+    Raw,
+}
+
+/// A TTY-like line discipline between the keyboard and readers of the console.
+struct Tty {
+    mode: Mode,
+    line: [u8; LINE_MAX],
+    line_len: usize,
+    /// Input ready for readers, as UTF-8.
+    queue: [u8; QUEUE_SIZE],
+    head: usize,
+    len: usize,
+}
+
+static SUBSCRIBER: Once<SubscriberId> = Once::new();
+static TTY: Mutex<Tty> = Mutex::new(Tty {
+    mode: Mode::Canonical,
+    line: [0; LINE_MAX],
+    line_len: 0,
+    queue: [0; QUEUE_SIZE],
+    head: 0,
+    len: 0,
+});
+/// Set by Ctrl+C in canonical mode. There are no processes to signal yet, so whoever
+/// owns the console has to check it with `take_interrupt`.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+impl Tty {
+    fn key(&mut self, c: char) {
+        let mut bytes = [0; 4];
+        let encoded = c.encode_utf8(&mut bytes).as_bytes();
+
+        if self.mode == Mode::Raw {
+            self.enqueue(encoded);
+            return;
+        }
+        let echo = config::CONSOLE_ECHO.get() != 0;
+        match c {
+            BACKSPACE => self.rub_out(echo),
+            CTRL_C => {
+                self.line_len = 0;
+                INTERRUPTED.store(true, Ordering::SeqCst);
+                if echo {
+                    print!("^C\n");
+                }
+            }
+            '\n' => {
+                let line = self.line;
+                self.enqueue(&line[..self.line_len]);
+                self.enqueue(b"\n");
+                self.line_len = 0;
+                if echo {
+                    print!("\n");
+                }
+            }
+            _ if self.line_len + encoded.len() <= LINE_MAX => {
+                self.line[self.line_len..self.line_len + encoded.len()].copy_from_slice(encoded);
+                self.line_len += encoded.len();
+                if echo {
+                    print!("{}", c);
+                }
+            }
+            // the line is full, drop keys until it is submitted
+            _ => {}
+        }
+    }
+
+    /// Removes the last character of the line, and its echo from the screen.
+    fn rub_out(&mut self, echo: bool) {
+        let mut removed = 0;
+        while self.line_len > 0 {
+            self.line_len -= 1;
+            removed += 1;
+            if self.line[self.line_len] & 0xc0 != 0x80 {
+                break;
+            }
+        }
+        if !echo {
+            return;
+        }
+        // the VGA writer shows one cell per byte of a non-ASCII character
+        interrupts::without_interrupts(|| {
+            let mut writer = vga_buffer::WRITER.lock();
+            for _ in 0..removed {
+                writer.backspace();
+            }
+        });
+    }
+
+    /// Appends input for readers, dropping what doesn't fit.
+    fn enqueue(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            if self.len == QUEUE_SIZE {
+                return;
+            }
+            self.queue[(self.head + self.len) % QUEUE_SIZE] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let count = buf.len().min(self.len);
+        for byte in buf[..count].iter_mut() {
+            *byte = self.queue[self.head];
+            self.head = (self.head + 1) % QUEUE_SIZE;
+        }
+        self.len -= count;
+        count
+    }
+}
+
+crate::kernel_driver!(CONSOLE_DRIVER, "console", init);
+
+/// Subscribes the console to key events.
+pub fn init() -> Result<(), &'static str> {
+    if SUBSCRIBER.r#try().is_none() {
+        let id = input::subscribe(input::keys_only, Some(handle_keys))
+            .ok_or("no free input subscriber")?;
+        SUBSCRIBER.call_once(|| id);
+    }
+    Ok(())
+}
+
+pub fn mode() -> Mode {
+    interrupts::without_interrupts(|| TTY.lock().mode)
+}
+
+/// Switches the line discipline. A partly typed canonical line is discarded.
+pub fn set_mode(mode: Mode) {
+    interrupts::without_interrupts(|| {
+        let mut tty = TTY.lock();
+        tty.mode = mode;
+        tty.line_len = 0;
+    });
+}
+
+/// Copies pending console input into `buf` and returns the number of bytes copied.
+/// Does not block, so this returns 0 when nothing was typed.
+pub fn read(buf: &mut [u8]) -> usize {
+    interrupts::without_interrupts(|| TTY.lock().read(buf))
+}
+
+/// Returns whether Ctrl+C was pressed since the last call.
+pub fn take_interrupt() -> bool {
+    INTERRUPTED.swap(false, Ordering::SeqCst)
+}
+
+fn handle_keys() {
+    let id = match SUBSCRIBER.r#try() {
+        Some(&id) => id,
+        None => return,
+    };
+
+    while let Some(event) = input::poll(id) {
+        match event {
+            InputEvent::Key(DecodedKey::Unicode(character)) => {
+                interrupts::without_interrupts(|| TTY.lock().key(character))
+            }
+            // keys without a character, like the arrows, have no place in a line yet
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use crate::config;
+use core::arch::x86_64::{__cpuid, __cpuid_count, _rdtsc};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Once;
+
+// CR4 bits; x86_64 has no wrapper for CR4 yet
+const CR4_SMEP: u64 = 1 << 20;
+const CR4_SMAP: u64 = 1 << 21;
+
+/// CPUID.1:ECX bit for RDRAND.
+const CPUID_RDRAND: u32 = 1 << 30;
+/// Intel recommends giving up on RDRAND after this many failures in a row.
+const RDRAND_RETRIES: usize = 10;
+
+// CPUID.(EAX=7, ECX=0):EBX bits
+const CPUID_SMEP: u32 = 1 << 7;
+const CPUID_SMAP: u32 = 1 << 20;
+
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+static BOOT_SEED: Once<u64> = Once::new();
+
+/// Protection features found by `init`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Protections {
+    /// The kernel can't run code from user pages.
+    pub smep: bool,
+    /// The kernel can't touch user pages outside `stac`/`clac`.
+    pub smap: bool,
+}
+
+/// Enables SMEP and SMAP where the CPU has them, and returns which ones it has.
+///
+/// Nothing is mapped user accessible yet, so neither changes what the kernel may do today.
+pub fn init() -> Protections {
+    let protections = detect();
+    let mut cr4 = read_cr4();
+    if protections.smep {
+        cr4 |= CR4_SMEP;
+    }
+    if protections.smap {
+        cr4 |= CR4_SMAP;
+    }
+    unsafe { write_cr4(cr4) };
+    SMAP_ENABLED.store(protections.smap, Ordering::SeqCst);
+    protections
+}
+
+fn detect() -> Protections {
+    // leaf 7 is only there if the highest basic leaf reaches it
+    if unsafe { __cpuid(0).eax } < 7 {
+        return Protections { smep: false, smap: false };
+    }
+    let ebx = unsafe { __cpuid_count(7, 0).ebx };
+    Protections {
+        smep: ebx & CPUID_SMEP != 0,
+        smap: ebx & CPUID_SMAP != 0,
+    }
+}
+
+/// Returns a random number from the CPU's hardware generator, or `None` if the CPU has no
+/// RDRAND or the generator keeps failing.
+pub fn rdrand() -> Option<u64> {
+    if unsafe { __cpuid(1).ecx } & CPUID_RDRAND == 0 {
+        return None;
+    }
+    for _ in 0..RDRAND_RETRIES {
+        let (value, ok): (u64, u8);
+        // the carry flag reports whether a random number was ready
+        unsafe { asm!("rdrand $0; setc $1" : "=r"(value), "=r"(ok) :: "cc" : "volatile") };
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Returns the seed every randomized decision of this boot is derived from. It is picked
+/// with RDRAND, or the TSC where that is missing, on first use, unless the
+/// `boot.deterministic` knob is set; then it is the `boot.seed` knob.
+///
+/// `kernel_main` prints it, so a boot can be replayed with `boot.deterministic=1` and
+/// `boot.seed` set to the printed value.
+pub fn boot_seed() -> u64 {
+    *BOOT_SEED.call_once(|| {
+        if config::DETERMINISTIC.get() != 0 {
+            config::BOOT_SEED.get() as u64
+        } else {
+            rdrand().unwrap_or_else(|| unsafe { _rdtsc() })
+        }
+    })
+}
+
+/// Allows the kernel to access user pages until the next `clac`. For the routines that
+/// copy from and to user memory; keep the window as small as possible.
+///
+/// Does nothing without SMAP, where the instruction would fault.
+#[inline]
+pub fn stac() {
+    if SMAP_ENABLED.load(Ordering::Relaxed) {
+        unsafe { asm!("stac" :::: "volatile") };
+    }
+}
+
+/// Forbids the kernel to access user pages again after `stac`.
+#[inline]
+pub fn clac() {
+    if SMAP_ENABLED.load(Ordering::Relaxed) {
+        unsafe { asm!("clac" :::: "volatile") };
+    }
+}
+
+fn read_cr4() -> u64 {
+    let value: u64;
+    unsafe { asm!("mov %cr4, $0" : "=r"(value)) };
+    value
+}
+
+/// Unsafe because clearing or setting the wrong bits can break paging and memory safety.
+unsafe fn write_cr4(value: u64) {
+    asm!("mov $0, %cr4" :: "r"(value) : "memory" : "volatile");
+}
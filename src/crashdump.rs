@@ -0,0 +1,193 @@
+use crate::compress;
+use crate::serial::SERIAL1;
+use crate::{backtrace, config, interrupts, HEAP_ALLOCATOR};
+use core::fmt::Write;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::registers::control::{Cr0, Cr2, Cr3};
+use x86_64::registers::rflags;
+
+/// Identifies the dump format version.
+const MAGIC: &[u8; 8] = b"OSRDUMP1";
+const FIELDS: usize = 9;
+const MAX_FRAMES: usize = 16;
+/// The magic, the fields, then a frame count and the frames, all eight bytes each.
+const DUMP_SIZE: usize = 8 + FIELDS * 8 + 8 + MAX_FRAMES * 8;
+/// Base64 characters per line, as in PEM.
+const LINE_LENGTH: usize = 76;
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Registers sampled at the start of the panic handler.
+#[derive(Debug, Clone, Copy)]
+pub struct Registers {
+    pub rsp: u64,
+    pub rbp: u64,
+    pub rflags: u64,
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+}
+
+impl Registers {
+    #[inline(always)]
+    pub fn capture() -> Registers {
+        let (rsp, rbp): (u64, u64);
+        unsafe {
+            asm!("mov %rsp, $0" : "=r"(rsp) ::: "volatile");
+            asm!("mov %rbp, $0" : "=r"(rbp) ::: "volatile");
+        }
+        Registers {
+            rsp,
+            rbp,
+            rflags: rflags::read_raw(),
+            cr0: Cr0::read_raw(),
+            cr2: Cr2::read().as_u64(),
+            cr3: Cr3::read().0.start_address().as_u64(),
+        }
+    }
+}
+
+struct Dump {
+    bytes: [u8; DUMP_SIZE],
+    len: usize,
+}
+
+impl Dump {
+    fn push(&mut self, value: u64) {
+        for i in 0..8 {
+            self.bytes[self.len + i] = (value >> (8 * i)) as u8;
+        }
+        self.len += 8;
+    }
+}
+
+/// Writes a crash dump to the serial port as a base64 blob between BEGIN and END lines.
+/// If the `panic.crash_dump` knob is 2, the dump is compressed as an LZ4 block first and
+/// the lines say `LZ4 CRASH DUMP`; it decompresses to at most 216 bytes.
+///
+/// The dump is little endian: the magic `OSRDUMP1`, then rsp, rbp, rflags, cr0, cr2, cr3,
+/// the timer tick, the peak heap usage and peak hole count (all ones if the heap was
+/// locked), and finally the number of backtrace frames followed by their addresses.
+/// There is no log ring or task list to include yet.
+///
+/// Only for the panic path, once the other CPUs are stopped: it breaks the serial port's
+/// lock. Everywhere else use `try_write`.
+pub fn write(registers: &Registers) {
+    let dump = collect(registers);
+    // the panic may have happened while the port was locked by this very CPU
+    unsafe { SERIAL1.force_unlock() };
+    without_interrupts(|| emit(&dump, &mut *SERIAL1.lock()));
+}
+
+/// Writes the same crash dump as `write`, unless the serial port is locked: then nothing
+/// is written and it returns false. Safe to call from interrupt handlers.
+pub fn try_write(registers: &Registers) -> bool {
+    let dump = collect(registers);
+    without_interrupts(|| match SERIAL1.try_lock() {
+        Some(mut serial) => {
+            emit(&dump, &mut *serial);
+            true
+        }
+        None => false,
+    })
+}
+
+fn collect(registers: &Registers) -> Dump {
+    let mut dump = Dump {
+        bytes: [0; DUMP_SIZE],
+        len: 0,
+    };
+    dump.bytes[..8].copy_from_slice(MAGIC);
+    dump.len = 8;
+
+    dump.push(registers.rsp);
+    dump.push(registers.rbp);
+    dump.push(registers.rflags);
+    dump.push(registers.cr0);
+    dump.push(registers.cr2);
+    dump.push(registers.cr3);
+    dump.push(interrupts::ticks() as u64);
+    match HEAP_ALLOCATOR.try_lock() {
+        Some(heap) => {
+            dump.push(heap.peak_used() as u64);
+            dump.push(heap.peak_holes() as u64);
+        }
+        None => {
+            dump.push(!0);
+            dump.push(!0);
+        }
+    }
+
+    let mut frames = [0u64; MAX_FRAMES];
+    let mut count = 0;
+    backtrace::walk(|addr| {
+        if count < MAX_FRAMES {
+            frames[count] = addr as u64;
+            count += 1;
+        }
+    });
+    dump.push(count as u64);
+    for &frame in &frames[..count] {
+        dump.push(frame);
+    }
+    dump
+}
+
+/// Prints `dump` to `out`, compressed if the `panic.crash_dump` knob asks for it. Output
+/// errors are ignored, there is nowhere left to report them.
+fn emit(dump: &Dump, out: &mut impl Write) {
+    let mut compressed = [0; compress::max_compressed_size(DUMP_SIZE)];
+    let compressed_len = if config::PANIC_CRASH_DUMP.get() == 2 {
+        compress::compress(&dump.bytes[..dump.len], &mut compressed)
+    } else {
+        None
+    };
+    match compressed_len {
+        Some(len) => {
+            let _ = writeln!(out, "-----BEGIN LZ4 CRASH DUMP-----");
+            print_base64(&compressed[..len], out);
+            let _ = writeln!(out, "-----END LZ4 CRASH DUMP-----");
+        }
+        None => {
+            let _ = writeln!(out, "-----BEGIN CRASH DUMP-----");
+            print_base64(&dump.bytes[..dump.len], out);
+            let _ = writeln!(out, "-----END CRASH DUMP-----");
+        }
+    }
+}
+
+/// Prints `bytes` base64 encoded, `LINE_LENGTH` characters per line.
+fn print_base64(bytes: &[u8], out: &mut impl Write) {
+    let mut line = [0u8; LINE_LENGTH];
+    let mut line_len = 0;
+    for chunk in bytes.chunks(3) {
+        for &c in encode(chunk).iter() {
+            line[line_len] = c;
+            line_len += 1;
+            if line_len == LINE_LENGTH {
+                print_line(&line[..line_len], out);
+                line_len = 0;
+            }
+        }
+    }
+    if line_len > 0 {
+        print_line(&line[..line_len], out);
+    }
+}
+
+/// Encodes up to three bytes as four base64 characters, padded with `=`.
+fn encode(chunk: &[u8]) -> [u8; 4] {
+    let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+    let group = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+
+    let mut out = [b'='; 4];
+    for i in 0..=chunk.len() {
+        out[i] = BASE64[(group >> (18 - 6 * i) & 0x3f) as usize];
+    }
+    out
+}
+
+fn print_line(line: &[u8], out: &mut impl Write) {
+    // only base64 characters end up here
+    let _ = writeln!(out, "{}", core::str::from_utf8(line).unwrap_or(""));
+}
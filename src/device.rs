@@ -0,0 +1,114 @@
+//! An inventory of the hardware the kernel knows about and whether it is in use, so a probe
+//! or driver that failed shows up in one place, with the reason.
+//!
+//! There is no PCI enumeration and the other CPUs are never started, so the tree only has
+//! the boot CPU, the legacy ISA devices and the registered drivers.
+
+use crate::{apic, driver, keyboard, println, serial};
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Column the statuses printed by `lsdev` start at.
+const NAME_WIDTH: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// Found and in use.
+    Attached,
+    /// Found, but bringing it up failed.
+    Failed(&'static str),
+    /// Not found, or not brought up yet.
+    Absent(&'static str),
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Status::Attached => write!(f, "attached"),
+            Status::Failed(reason) => write!(f, "failed: {}", reason),
+            Status::Absent(reason) => write!(f, "absent: {}", reason),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Device {
+    pub name: &'static str,
+    /// The device this one hangs off, `None` for the roots.
+    pub parent: Option<&'static str>,
+    pub status: Status,
+}
+
+/// Probes the hardware again and returns every device, each one after its parent.
+pub fn tree() -> Vec<Device> {
+    let mut devices = Vec::new();
+    let mut add = |name, parent, status| devices.push(Device { name, parent, status });
+
+    add("cpu0", None, Status::Attached);
+    let apic = if apic::is_enabled() {
+        Status::Attached
+    } else {
+        Status::Absent("not initialized")
+    };
+    add("local-apic", Some("cpu0"), apic);
+
+    add("isa", None, Status::Attached);
+    add("ps2-controller", Some("isa"), probe_ps2());
+    add("com1", Some("isa"), probe_com1());
+    // every PC boots in VGA text mode, and nothing switches away from it
+    add("vga-text", Some("isa"), Status::Attached);
+
+    add("drivers", None, Status::Attached);
+    for (index, registered) in driver::drivers().iter().enumerate() {
+        let status = match driver::init_result(index) {
+            Some(Ok(())) => Status::Attached,
+            Some(Err(err)) => Status::Failed(err),
+            None => Status::Absent("not initialized"),
+        };
+        add(registered.name, Some("drivers"), status);
+    }
+    devices
+}
+
+/// Prints the device tree with the status of each device. `kernel_main` calls this once the
+/// drivers and the local APIC are up.
+pub fn lsdev() {
+    let devices = tree();
+    for device in &devices {
+        let indent = 2 * depth(&devices, device);
+        println!(
+            "{:indent$}{:<width$} {}",
+            "",
+            device.name,
+            device.status,
+            indent = indent,
+            width = NAME_WIDTH.saturating_sub(indent)
+        );
+    }
+}
+
+fn depth(devices: &[Device], device: &Device) -> usize {
+    let mut depth = 0;
+    let mut parent = device.parent;
+    while let Some(name) = parent {
+        depth += 1;
+        parent = devices.iter().find(|other| other.name == name).and_then(|other| other.parent);
+    }
+    depth
+}
+
+fn probe_ps2() -> Status {
+    // nothing drives the bus without a controller, so the port reads all ones
+    match unsafe { keyboard::status_port().read() } {
+        0xff => Status::Absent("no controller at port 0x64"),
+        _ => Status::Attached,
+    }
+}
+
+fn probe_com1() -> Status {
+    if serial::is_present() {
+        Status::Attached
+    } else {
+        Status::Absent("no UART at port 0x3f8")
+    }
+}
@@ -0,0 +1,78 @@
+use crate::{println, serial_println};
+use core::{mem, slice};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Drivers past this many are still brought up, but their result is not kept.
+const MAX_DRIVERS: usize = 32;
+
+/// The result of each driver's init function, by its index in `drivers()`.
+static RESULTS: Mutex<[Option<Result<(), &'static str>>; MAX_DRIVERS]> =
+    Mutex::new([None; MAX_DRIVERS]);
+
+/// A driver brought up by `init_all`, registered with `kernel_driver!`.
+pub struct Driver {
+    pub name: &'static str,
+    pub init: fn() -> Result<(), &'static str>,
+}
+
+/// Registers a driver, so that `init_all` calls its init function at boot.
+///
+/// The descriptor goes into the `kernel_drivers` linker section. The linker provides
+/// the bounds of that section as `__start_kernel_drivers` and `__stop_kernel_drivers`.
+///
+/// ```ignore
+/// kernel_driver!(CONSOLE_DRIVER, "console", init);
+/// ```
+#[macro_export]
+macro_rules! kernel_driver {
+    ($static_name:ident, $name:expr, $init:expr) => {
+        #[used]
+        #[link_section = "kernel_drivers"]
+        static $static_name: $crate::driver::Driver = $crate::driver::Driver {
+            name: $name,
+            init: $init,
+        };
+    };
+}
+
+extern "C" {
+    static __start_kernel_drivers: Driver;
+    static __stop_kernel_drivers: Driver;
+}
+
+/// Returns all registered drivers, in link order.
+pub fn drivers() -> &'static [Driver] {
+    unsafe {
+        let start = &__start_kernel_drivers as *const Driver;
+        let end = &__stop_kernel_drivers as *const Driver;
+        let count = (end as usize - start as usize) / mem::size_of::<Driver>();
+        slice::from_raw_parts(start, count)
+    }
+}
+
+/// Calls the init function of every registered driver.
+///
+/// Runs after the IDT is loaded and before interrupts are enabled. Drivers must not
+/// depend on each other, since link order is not under our control. A failing driver
+/// is reported and skipped.
+pub fn init_all() {
+    for (index, driver) in drivers().iter().enumerate() {
+        let result = (driver.init)();
+        without_interrupts(|| {
+            if let Some(slot) = RESULTS.lock().get_mut(index) {
+                *slot = Some(result);
+            }
+        });
+        match result {
+            Ok(()) => serial_println!("[driver] {}", driver.name),
+            Err(err) => println!("driver {} failed: {}", driver.name, err),
+        }
+    }
+}
+
+/// Returns what the init function of the driver at `index` in `drivers()` returned, or
+/// `None` if `init_all` hasn't called it.
+pub fn init_result(index: usize) -> Option<Result<(), &'static str>> {
+    without_interrupts(|| RESULTS.lock().get(index).and_then(|result| *result))
+}
@@ -0,0 +1,80 @@
+use crate::hole::{align_up, HoleList};
+use alloc::alloc::{AllocErr, Layout};
+use core::ptr::NonNull;
+
+/// Size of the static region early allocations come from (64 KiB).
+pub const EARLY_HEAP_SIZE: usize = 64 * 1024;
+
+#[repr(align(4096))]
+struct EarlyHeap([u8; EARLY_HEAP_SIZE]);
+
+// in .bss, so it is mapped writable by the bootloader before the kernel runs
+static mut EARLY_HEAP: EarlyHeap = EarlyHeap([0; EARLY_HEAP_SIZE]);
+
+/// Hands out memory from a static region before the heap is set up, for the subsystems that
+/// run before paging is ready, like copies of the memory map.
+///
+/// Memory is never given back: `dealloc` of an early allocation does nothing. When the heap
+/// is initialized, `donate` hands the unused rest of the region to the hole list.
+pub struct BumpAllocator {
+    /// Offset of the first free byte in the region.
+    next: usize,
+    donated: bool,
+}
+
+impl BumpAllocator {
+    pub const fn new() -> BumpAllocator {
+        BumpAllocator {
+            next: 0,
+            donated: false,
+        }
+    }
+
+    fn start() -> usize {
+        unsafe { &EARLY_HEAP as *const EarlyHeap as usize }
+    }
+
+    /// Fails once the region is used up, and always after `donate`.
+    pub fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
+        if self.donated {
+            return Err(AllocErr);
+        }
+        let start = Self::start();
+        let addr = align_up(start + self.next, layout.align());
+        let end = addr.checked_add(layout.size()).ok_or(AllocErr)?;
+        if end > start + EARLY_HEAP_SIZE {
+            return Err(AllocErr);
+        }
+        self.next = end - start;
+        NonNull::new(addr as *mut u8).ok_or(AllocErr)
+    }
+
+    /// Returns whether `addr` lies in memory handed out by `alloc`. Memory given away with
+    /// `donate` doesn't count.
+    pub fn contains(&self, addr: usize) -> bool {
+        let start = Self::start();
+        start <= addr && addr < start + self.next
+    }
+
+    /// Bytes handed out by `alloc`.
+    pub fn used(&self) -> usize {
+        self.next
+    }
+
+    /// Stops serving allocations and returns the `(address, size)` of the unused rest of the
+    /// region, for `HeapAllocator::add_region`. Returns `None` if it is too small for a hole
+    /// or was donated already.
+    pub fn donate(&mut self) -> Option<(usize, usize)> {
+        if self.donated {
+            return None;
+        }
+        self.donated = true;
+        let start = Self::start();
+        let addr = align_up(start + self.next, 16);
+        let size = (start + EARLY_HEAP_SIZE).saturating_sub(addr);
+        if size < 2 * HoleList::min_size() {
+            return None;
+        }
+        Some((addr, size))
+    }
+}
@@ -1,30 +1,26 @@
 use lazy_static::lazy_static;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
+use x86_64::structures::DescriptorTablePointer;
 use x86_64::VirtAddr;
+use crate::println;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// Size of the guarded double fault stack that replaces the boot one, in pages.
+pub const DOUBLE_FAULT_STACK_PAGES: usize = 4;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
-    };
-}
+const BOOT_STACK_SIZE: usize = 4096;
+/// The double fault stack until paging is set up. Nothing catches an overflow of it.
+static mut BOOT_DOUBLE_FAULT_STACK: [u8; BOOT_STACK_SIZE] = [0; BOOT_STACK_SIZE];
+
+/// Only written by `init` before it is loaded, and by `set_double_fault_stack`.
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
 
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &TSS }));
         (
             gdt,
             Selectors {
@@ -44,9 +40,68 @@ pub fn init() {
     use x86_64::instructions::segmentation::set_cs;
     use x86_64::instructions::tables::load_tss;
 
+    unsafe {
+        let stack_start = VirtAddr::from_ptr(&BOOT_DOUBLE_FAULT_STACK);
+        TSS.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stack_start + BOOT_STACK_SIZE;
+    }
+
     GDT.0.load();
     unsafe {
         set_cs(GDT.1.code_selector);
         load_tss(GDT.1.tss_selector);
     }
 }
+
+/// Switches the double fault handler to the stack ending at `top`, such as one from
+/// `memory::alloc_stack` with a guard page below it.
+///
+/// # Unsafe
+/// The stack must be mapped and not used for anything else.
+pub unsafe fn set_double_fault_stack(top: VirtAddr) {
+    TSS.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = top;
+}
+
+/// Prints every present descriptor of the loaded GDT with its selector.
+pub fn dump() {
+    let mut gdtr = DescriptorTablePointer { limit: 0, base: 0 };
+    unsafe { asm!("sgdt ($0)" :: "r"(&mut gdtr) : "memory") };
+
+    let base = gdtr.base as usize;
+    let count = (usize::from(gdtr.limit) + 1) / 8;
+    let mut index = 0;
+    while index < count {
+        let raw = unsafe { *((base + index * 8) as *const u64) };
+        let access = (raw >> 40) as u8;
+        let dpl = (access >> 5) & 0b11;
+
+        if access & 0x80 == 0 {
+            // not present
+            index += 1;
+        } else if access & 0x10 != 0 {
+            let kind = if access & 0x08 != 0 { "code" } else { "data" };
+            println!(
+                "{:#04x}: {} segment, dpl {}, long mode {}",
+                index * 8,
+                kind,
+                dpl,
+                raw & (1 << 53) != 0
+            );
+            index += 1;
+        } else {
+            // system descriptors such as the TSS take two entries in long mode
+            let high = unsafe { *((base + (index + 1) * 8) as *const u64) };
+            let segment_base =
+                ((raw >> 16) & 0xff_ffff) | (((raw >> 56) & 0xff) << 24) | (high << 32);
+            let limit = (raw & 0xffff) | (((raw >> 48) & 0xf) << 16);
+            println!(
+                "{:#04x}: system segment type {:#x}, dpl {}, base {:#x}, limit {:#x}",
+                index * 8,
+                access & 0xf,
+                dpl,
+                segment_base,
+                limit
+            );
+            index += 2;
+        }
+    }
+}
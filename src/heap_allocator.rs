@@ -1,4 +1,6 @@
+use crate::early_alloc::BumpAllocator;
 use crate::hole::{HoleList, Hole, align_up};
+use crate::large_alloc::{LargeAllocator, PageProvider, PAGE_SIZE};
 use alloc::alloc::{Alloc, AllocErr, Layout};
 use core::ptr::NonNull;
 use core::ptr::null_mut;
@@ -9,44 +11,142 @@ use core::ops::Deref;
 
 use spin::Mutex;
 
+use crate::stats::{self, Counter, Histogram, Stat};
+use crate::{config, interrupts, kasan, serial_println, trace};
+use x86_64::instructions::interrupts::without_interrupts;
+
 /// A fixed size heap backed by a linked list of free memory blocks.
 pub struct HeapAllocator {
     bottom: usize,
     size: usize,
     holes: HoleList,
+    /// Bytes in regions added with `add_region`, outside `[bottom, top)`.
+    extra: usize,
+    large: LargeAllocator,
+    /// Serves allocations until `init`.
+    early: BumpAllocator,
+    grow: Option<GrowFn>,
+    used: usize,
+    /// Bytes in the pages of large allocations.
+    large_used: usize,
+    /// Live allocations, large ones included.
+    allocations: usize,
+    peak_used: usize,
+    peak_holes: usize,
 }
 
+/// Maps `size` more bytes at `top`, the end of the heap, returning whether that worked.
+///
+/// Called with the heap locked, so it must not allocate.
+pub type GrowFn = fn(top: usize, size: usize) -> bool;
+
 impl HeapAllocator {
     pub const fn empty() -> HeapAllocator {
         HeapAllocator {
             bottom: 0,
             size: 0,
             holes: HoleList::empty(),
+            extra: 0,
+            large: LargeAllocator::empty(),
+            early: BumpAllocator::new(),
+            grow: None,
+            used: 0,
+            large_used: 0,
+            allocations: 0,
+            peak_used: 0,
+            peak_holes: 0,
         }
     }
 
     /// init help with given start point and size
-    /// The allocator will claim memory `[heap_bottom, heap_bottom + heap_size)`
+    /// The allocator will claim memory `[heap_bottom, heap_bottom + heap_size)`.
+    /// What the early allocator didn't hand out is added to the hole list; early
+    /// allocations stay valid but are never freed.
     pub unsafe fn init(&mut self, heap_bottom: usize, heap_size: usize) {
 
         self.bottom = heap_bottom;
         self.size = heap_size;
+        kasan::init(heap_bottom, heap_size);
         self.holes = HoleList::new(heap_bottom, heap_size);
+        self.holes.set_store(Some(kasan::store_free::<Hole>));
+        self.extra = 0;
+        self.used = 0;
+        self.large_used = 0;
+        self.allocations = 0;
+        self.peak_used = 0;
+        self.peak_holes = self.holes.len();
+        if let Some((addr, size)) = self.early.donate() {
+            self.add_region(addr, size);
+        }
+    }
+
+    /// Routes allocations above a page to whole pages mapped by `provider` in the virtual
+    /// region starting at `start`, instead of the hole list.
+    pub unsafe fn init_large(&mut self, start: usize, provider: &'static mut dyn PageProvider) {
+        self.large.init(start, provider);
     }
 
 
+    /// Donates the free memory `[addr, addr + size)` to the hole list, for memory that is
+    /// not contiguous with the heap. The heap's `bottom` and `top` don't change, and with the
+    /// `kasan` feature accesses to the region are not checked.
+    ///
+    /// # Unsafe
+    /// The region must be mapped, unused, and outside the heap.
+    pub unsafe fn add_region(&mut self, addr: usize, size: usize) {
+        self.holes.extend(addr, size);
+        self.extra += size;
+        self.update_peaks();
+    }
+
+    /// Lets the heap grow through `grow` when the hole list has no fitting block, in steps of
+    /// at least the `heap.growth_step` knob. The top of the heap must be page aligned.
+    pub fn set_grow(&mut self, grow: Option<GrowFn>) {
+        assert_eq!(self.top() % PAGE_SIZE, 0, "heap top is not page aligned");
+        self.grow = grow;
+    }
+
     /// call allocate_first_fit in Holes;
     /// If the layout size is smaller than the min_size, function will extend the layout
     /// to the min_size;
+    /// Allocations above a page go to the large allocation path first, if it is set up.
+    /// Before `init`, allocations come from the early allocator's static region.
     pub fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
+        if self.size == 0 {
+            return self.early.alloc(layout);
+        }
+
+        if layout.size() > config::HEAP_LARGE_THRESHOLD.get() && layout.align() <= PAGE_SIZE {
+            if let Some(allocation) = self.large.alloc(layout.size()) {
+                self.large_used += align_up(layout.size(), PAGE_SIZE);
+                self.allocations += 1;
+                self.update_peaks();
+                return Ok(allocation);
+            }
+        }
+
+        let requested = layout.size();
         let mut size = layout.size();
         if size < HoleList::min_size() {
             size = HoleList::min_size();
         }
+        // keeps holes granule aligned, so no shadow byte is shared by two allocations
+        let size = kasan::round_size(size);
 
         let layout = Layout::from_size_align(size, layout.align()).unwrap();
 
-        self.holes.alloc(layout)
+        let allocation = match self.holes.alloc(layout) {
+            Ok(allocation) => allocation,
+            Err(AllocErr) => {
+                self.grow(size + layout.align())?;
+                self.holes.alloc(layout)?
+            }
+        };
+        kasan::unpoison(allocation.as_ptr() as usize, requested);
+        self.used += size;
+        self.allocations += 1;
+        self.update_peaks();
+        Ok(allocation)
     }
 
 
@@ -54,13 +154,57 @@ impl HeapAllocator {
     /// If the layout size is smaller than the min_size, function will extend the layout
     /// to the min_size;
     pub unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        if self.early.contains(ptr.as_ptr() as usize) {
+            return;
+        }
+        if self.large.contains(ptr.as_ptr() as usize) {
+            self.large.dealloc(ptr, layout.size());
+            self.large_used -= align_up(layout.size(), PAGE_SIZE);
+            self.allocations -= 1;
+            return;
+        }
+
+        // freeing memory that is not allocated is a double or invalid free
+        kasan::check(ptr.as_ptr() as usize, layout.size(), "free");
         let mut size = layout.size();
         if size < HoleList::min_size() {
             size = HoleList::min_size();
         }
+        let size = kasan::round_size(size);
         let layout = Layout::from_size_align(size, layout.align()).unwrap();
 
+        // poisoned first, since the hole list writes its header into the freed block
+        kasan::poison(ptr.as_ptr() as usize, size);
         self.holes.deallocate(ptr, layout);
+        self.used -= size;
+        self.allocations -= 1;
+        self.update_peaks();
+    }
+
+    /// Extends the heap by at least `needed` bytes through the grow callback.
+    fn grow(&mut self, needed: usize) -> Result<(), AllocErr> {
+        let grow = self.grow.ok_or(AllocErr)?;
+        let step = align_up(needed.max(config::HEAP_GROWTH_STEP.get()), PAGE_SIZE);
+        let top = self.top();
+        if !grow(top, step) {
+            return Err(AllocErr);
+        }
+
+        // merges with the last hole if that one reaches up to the old top
+        kasan::extend(top, step);
+        unsafe { self.holes.extend(top, step) };
+        self.size += step;
+        Ok(())
+    }
+
+    fn update_peaks(&mut self) {
+        let used = self.used + self.large_used;
+        if used > self.peak_used {
+            self.peak_used = used;
+        }
+        if self.holes.len() > self.peak_holes {
+            self.peak_holes = self.holes.len();
+        }
     }
 
     /// Returns the bottom address of the heap.
@@ -78,8 +222,53 @@ impl HeapAllocator {
         self.bottom + self.size
     }
 
-    pub fn first_hole(&self) -> Option<(usize, usize)> {
-        self.holes.first_hole()
+    /// Returns the `(address, size)` of every free block of the hole list, in address order.
+    pub fn holes(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.holes.iter()
+    }
+
+    /// Returns the bytes handed out by the early allocator, which are never freed.
+    pub fn early_used(&self) -> usize {
+        self.early.used()
+    }
+
+    /// Returns the bytes of the hole list in use, including the padding of small allocations.
+    /// Large allocations are not included, they have pages of their own: see `large_used`.
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    /// Returns the bytes in the pages of large allocations.
+    pub fn large_used(&self) -> usize {
+        self.large_used
+    }
+
+    /// Returns the free bytes of the hole list, which may be split into many holes.
+    pub fn free(&self) -> usize {
+        self.size + self.extra - self.used
+    }
+
+    /// Returns the size of the largest hole. An allocation failing with more free bytes
+    /// than its size means the heap is too fragmented rather than full, though alignment
+    /// may shave a few bytes off the block.
+    pub fn largest_free_block(&self) -> usize {
+        self.holes.iter().map(|(_, size)| size).max().unwrap_or(0)
+    }
+
+    /// Returns the number of live allocations, large ones included.
+    pub fn allocation_count(&self) -> usize {
+        self.allocations
+    }
+
+    /// Returns the largest number of bytes that were ever in use at once, in the hole list
+    /// and in the pages of large allocations together.
+    pub fn peak_used(&self) -> usize {
+        self.peak_used
+    }
+
+    /// Returns the largest number of holes the free list ever had, i.e. the peak fragmentation.
+    pub fn peak_holes(&self) -> usize {
+        self.peak_holes
     }
 
 }
@@ -106,11 +295,9 @@ impl GlobalHeapAllocator {
     }
 
     pub unsafe fn new(heap_bottom: usize, heap_size: usize) -> GlobalHeapAllocator {
-        GlobalHeapAllocator(Mutex::new(HeapAllocator {
-            bottom: heap_bottom,
-            size: heap_size,
-            holes: HoleList::new(heap_bottom, heap_size),
-        }))
+        let mut heap = HeapAllocator::empty();
+        heap.init(heap_bottom, heap_size);
+        GlobalHeapAllocator(Mutex::new(heap))
     }
 }
 
@@ -127,11 +314,25 @@ impl Deref for GlobalHeapAllocator {
 // Implement GlobalAllocator as required by alloc
 unsafe impl GlobalAlloc for GlobalHeapAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        self.0
-            .lock()
-            .alloc(layout)
-            .ok()
-            .map_or(0 as *mut u8, |allocation| allocation.as_ptr())
+        ALLOC_SIZES.record(layout.size());
+        trace::alloc(layout.size());
+        let mut heap = self.0.lock();
+        match heap.alloc(layout) {
+            Ok(allocation) => allocation.as_ptr(),
+            Err(AllocErr) => {
+                let failure = AllocFailure {
+                    layout,
+                    free: heap.free(),
+                    largest_free_block: heap.largest_free_block(),
+                    holes: heap.holes.len(),
+                    tick: interrupts::ticks(),
+                };
+                drop(heap);
+                ALLOC_FAILURES.inc();
+                record_failure(failure);
+                null_mut()
+            }
+        }
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
@@ -141,5 +342,92 @@ unsafe impl GlobalAlloc for GlobalHeapAllocator {
     }
 }
 
+static ALLOC_SIZES: Histogram = Histogram::new("heap.alloc_size");
+static ALLOC_FAILURES: Counter = Counter::new("heap.alloc_failures");
 
+/// Registers the statistics of the global allocator with `stats`.
+pub fn register_stats() {
+    stats::register(Stat::Histogram(&ALLOC_SIZES));
+    stats::register(Stat::Counter(&ALLOC_FAILURES));
+}
+
+/// Number of allocation failures kept by `recent_failures`.
+pub const FAILURE_RING_SIZE: usize = 8;
+
+/// A failed allocation, with a snapshot of the heap at that moment.
+#[derive(Debug, Clone, Copy)]
+pub struct AllocFailure {
+    pub layout: Layout,
+    /// Free bytes in the hole list.
+    pub free: usize,
+    /// Size of the largest hole.
+    pub largest_free_block: usize,
+    /// Number of holes in the hole list.
+    pub holes: usize,
+    /// Timer ticks since boot.
+    pub tick: usize,
+}
+
+struct FailureRing {
+    failures: [Option<AllocFailure>; FAILURE_RING_SIZE],
+    next: usize,
+}
+
+static FAILURES: Mutex<FailureRing> = Mutex::new(FailureRing {
+    failures: [None; FAILURE_RING_SIZE],
+    next: 0,
+});
+
+fn record_failure(failure: AllocFailure) {
+    without_interrupts(|| {
+        let mut ring = FAILURES.lock();
+        let next = ring.next;
+        ring.failures[next] = Some(failure);
+        ring.next = (next + 1) % FAILURE_RING_SIZE;
+    });
+}
 
+/// Returns the most recent allocation failures of the global allocator, oldest first.
+pub fn recent_failures() -> [Option<AllocFailure>; FAILURE_RING_SIZE] {
+    without_interrupts(|| {
+        let ring = FAILURES.lock();
+        let mut failures = [None; FAILURE_RING_SIZE];
+        for i in 0..FAILURE_RING_SIZE {
+            failures[i] = ring.failures[(ring.next + i) % FAILURE_RING_SIZE];
+        }
+        failures
+    })
+}
+
+/// Prints the heap usage and its high-water marks to the serial port.
+///
+/// Uses `try_lock` since this also runs on the panic path, where the heap may be locked.
+pub fn print_peaks(heap: &GlobalHeapAllocator) {
+    match heap.try_lock() {
+        Some(heap) => serial_println!(
+            "heap: {} bytes in use, {} in large pages, {} free, {} allocations; peak {} bytes in \
+             use, peak {} holes",
+            heap.used(),
+            heap.large_used(),
+            heap.free(),
+            heap.allocation_count(),
+            heap.peak_used(),
+            heap.peak_holes()
+        ),
+        None => serial_println!("heap: locked, peaks unavailable"),
+    }
+}
+
+/// Asserts that the peak heap usage so far stays below the given number of bytes.
+#[macro_export]
+macro_rules! kassert_heap_below {
+    ($bytes:expr) => {{
+        let peak = $crate::HEAP_ALLOCATOR.lock().peak_used();
+        assert!(
+            peak < $bytes,
+            "heap peak usage of {} bytes exceeds the budget of {} bytes",
+            peak,
+            $bytes
+        );
+    }};
+}
@@ -0,0 +1,116 @@
+use crate::stats::{self, Counter, Stat};
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+const MAX_DEFERRED: usize = 16;
+
+/// Set by interrupt handlers whenever they leave work for the idle loop.
+static PENDING: AtomicBool = AtomicBool::new(false);
+
+static DEFERRED: Mutex<[Option<fn()>; MAX_DEFERRED]> = Mutex::new([None; MAX_DEFERRED]);
+
+// only the boot CPU runs the idle loop so far, so these are its numbers
+static IDLE_CYCLES: Counter = Counter::new("idle.cycles");
+static IDLE_ENTRIES: Counter = Counter::new("idle.entries");
+
+/// Marks work as pending, so the idle loop checks for it before halting again.
+pub fn wake() {
+    PENDING.store(true, Ordering::SeqCst);
+}
+
+/// Queues `work` to be run from the idle loop instead of interrupt context.
+///
+/// Returns false if the queue is full. Safe to call from interrupt handlers.
+pub fn defer(work: fn()) -> bool {
+    let queued = interrupts::without_interrupts(|| {
+        let mut slots = DEFERRED.lock();
+        match slots.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(work);
+                true
+            }
+            None => false,
+        }
+    });
+
+    if queued {
+        wake();
+    }
+    queued
+}
+
+/// Runs pending work and halts the CPU while there is none.
+///
+/// Sleeps with `mwait` where the CPU has it, else with `hlt`. Time spent asleep is counted
+/// in the `idle.cycles` statistic, in TSC cycles.
+pub fn run() -> ! {
+    let mwait = has_mwait();
+    stats::register(Stat::Counter(&IDLE_CYCLES));
+    stats::register(Stat::Counter(&IDLE_ENTRIES));
+
+    loop {
+        interrupts::disable();
+        if PENDING.swap(false, Ordering::SeqCst) {
+            interrupts::enable();
+            run_deferred();
+        } else {
+            sleep(mwait);
+        }
+    }
+}
+
+/// Returns the TSC cycles the idle loop spent asleep.
+pub fn idle_cycles() -> usize {
+    IDLE_CYCLES.get()
+}
+
+fn has_mwait() -> bool {
+    unsafe { __cpuid(1).ecx & (1 << 3) != 0 }
+}
+
+/// Sleeps until the next interrupt. Must be called with interrupts disabled and returns
+/// with them enabled.
+fn sleep(mwait: bool) {
+    let start = unsafe { _rdtsc() };
+    if mwait {
+        unsafe {
+            // a store to PENDING from another CPU then ends the wait without an interrupt
+            asm!("monitor" :: "{rax}"(&PENDING), "{ecx}"(0), "{edx}"(0) :: "volatile");
+            if PENDING.load(Ordering::SeqCst) {
+                interrupts::enable();
+            } else {
+                // hint 0 asks for C1, the same state `hlt` enters
+                asm!("sti; mwait" :: "{eax}"(0), "{ecx}"(0) :: "volatile");
+            }
+        }
+    } else {
+        // `sti` takes effect only after the next instruction, so an interrupt that
+        // queues work can't slip in between the check in `run` and the `hlt`
+        unsafe { asm!("sti; hlt" :::: "volatile") };
+    }
+    IDLE_CYCLES.add((unsafe { _rdtsc() } - start) as usize);
+    IDLE_ENTRIES.inc();
+}
+
+/// Halts the CPU for good. Used on fatal paths where no more work should be done.
+pub fn halt() -> ! {
+    loop {
+        interrupts::disable();
+        x86_64::instructions::hlt();
+    }
+}
+
+fn run_deferred() {
+    let work = interrupts::without_interrupts(|| {
+        let mut slots = DEFERRED.lock();
+        let work = *slots;
+        *slots = [None; MAX_DEFERRED];
+        work
+    });
+
+    for f in work.iter().filter_map(|slot| *slot) {
+        f();
+    }
+}
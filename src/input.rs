@@ -0,0 +1,135 @@
+use crate::idle;
+use pc_keyboard::DecodedKey;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+const MAX_SUBSCRIBERS: usize = 8;
+const QUEUE_SIZE: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+}
+
+/// An event from any input device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputEvent {
+    Key(DecodedKey),
+    MouseMove { dx: i16, dy: i16 },
+    MouseButton { button: MouseButton, pressed: bool },
+}
+
+/// Handle returned by `subscribe`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubscriberId(usize);
+
+/// Decides which events a subscriber receives.
+pub type Filter = fn(&InputEvent) -> bool;
+
+pub fn all_events(_event: &InputEvent) -> bool {
+    true
+}
+
+pub fn keys_only(event: &InputEvent) -> bool {
+    match event {
+        InputEvent::Key(_) => true,
+        _ => false,
+    }
+}
+
+pub fn mouse_only(event: &InputEvent) -> bool {
+    !keys_only(event)
+}
+
+#[derive(Clone, Copy)]
+struct Subscriber {
+    filter: Filter,
+    notify: Option<fn()>,
+    queue: [Option<InputEvent>; QUEUE_SIZE],
+    head: usize,
+    len: usize,
+    dropped: usize,
+}
+
+impl Subscriber {
+    fn push(&mut self, event: InputEvent) -> bool {
+        if self.len == QUEUE_SIZE {
+            self.dropped += 1;
+            return false;
+        }
+        self.queue[(self.head + self.len) % QUEUE_SIZE] = Some(event);
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<InputEvent> {
+        if self.len == 0 {
+            return None;
+        }
+        let event = self.queue[self.head].take();
+        self.head = (self.head + 1) % QUEUE_SIZE;
+        self.len -= 1;
+        event
+    }
+}
+
+static SUBSCRIBERS: Mutex<[Option<Subscriber>; MAX_SUBSCRIBERS]> =
+    Mutex::new([None; MAX_SUBSCRIBERS]);
+
+/// Registers a new event queue receiving the events `filter` accepts.
+///
+/// If `notify` is given, it is deferred to the idle loop whenever an event was queued, so
+/// the subscriber can drain its queue outside interrupt context. Returns `None` if all
+/// subscriber slots are taken.
+pub fn subscribe(filter: Filter, notify: Option<fn()>) -> Option<SubscriberId> {
+    interrupts::without_interrupts(|| {
+        let mut subscribers = SUBSCRIBERS.lock();
+        let index = subscribers.iter().position(|slot| slot.is_none())?;
+        subscribers[index] = Some(Subscriber {
+            filter,
+            notify,
+            queue: [None; QUEUE_SIZE],
+            head: 0,
+            len: 0,
+            dropped: 0,
+        });
+        Some(SubscriberId(index))
+    })
+}
+
+pub fn unsubscribe(id: SubscriberId) {
+    interrupts::without_interrupts(|| SUBSCRIBERS.lock()[id.0] = None);
+}
+
+/// Hands `event` to every subscriber whose filter accepts it. Called by the drivers,
+/// usually from interrupt handlers. Events for a full queue are dropped.
+pub fn publish(event: InputEvent) {
+    let mut notify = [None; MAX_SUBSCRIBERS];
+
+    interrupts::without_interrupts(|| {
+        let mut subscribers = SUBSCRIBERS.lock();
+        for (slot, notify) in subscribers.iter_mut().zip(notify.iter_mut()) {
+            if let Some(subscriber) = slot {
+                if (subscriber.filter)(&event) && subscriber.push(event) {
+                    *notify = subscriber.notify;
+                }
+            }
+        }
+    });
+
+    for f in notify.iter().filter_map(|f| *f) {
+        idle::defer(f);
+    }
+}
+
+/// Takes the oldest queued event of the given subscriber.
+pub fn poll(id: SubscriberId) -> Option<InputEvent> {
+    interrupts::without_interrupts(|| SUBSCRIBERS.lock()[id.0].as_mut()?.pop())
+}
+
+/// Returns how many events were dropped because the subscriber's queue was full.
+pub fn dropped(id: SubscriberId) -> usize {
+    interrupts::without_interrupts(|| SUBSCRIBERS.lock()[id.0].map_or(0, |s| s.dropped))
+}
@@ -3,9 +3,17 @@
 // for a Windows system.
 #![cfg(not(windows))]
 
-use crate::{gdt, hlt_loop, print, println};
+use crate::portio::PortRange;
+use crate::stats::{self, Counter, Stat};
+use crate::{
+    apic, gdt, idle, keyboard, memory, msr, pit, print, println, progress, serial_println, smp,
+    symbols, thermal, trace,
+};
 use lazy_static::lazy_static;
-use x86_64::structures::idt::{ExceptionStackFrame, InterruptDescriptorTable, PageFaultErrorCode};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::idt::{
+    Entry, ExceptionStackFrame, HandlerFunc, InterruptDescriptorTable, PageFaultErrorCode,
+};
 use pic8259_simple::ChainedPics;
 use spin;
 
@@ -18,24 +26,199 @@ pub const KEYBOARD_INTERRUPT_ID: u8 = TIMER_INTERRUPT_ID + 1;
 pub static PICS: spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
+crate::kernel_driver!(PIC_DRIVER, "pic", claim_pic_ports);
+
+// `ChainedPics` does its own port I/O, so the claims only keep other drivers off the ports
+fn claim_pic_ports() -> Result<(), &'static str> {
+    PortRange::claim(0x20..=0x21, "pic");
+    PortRange::claim(0xa0..=0xa1, "pic");
+    Ok(())
+}
+
+/// Priority classes for dynamically allocated vectors.
+///
+/// The local APIC prioritizes interrupts by `vector >> 4`, so each class is a range of whole
+/// priority groups, higher classes getting higher vectors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PriorityClass {
+    /// Software interrupts, vectors `0x30..0x80`.
+    Software,
+    /// Device interrupts such as MSI, vectors `0x80..0xe0`.
+    Device,
+    /// Inter-processor interrupts, vectors `0xe0..0xff`.
+    Ipi,
+}
+
+impl PriorityClass {
+    fn range(self) -> core::ops::Range<usize> {
+        match self {
+            PriorityClass::Software => 0x30..0x80,
+            PriorityClass::Device => 0x80..0xe0,
+            PriorityClass::Ipi => 0xe0..0xff,
+        }
+    }
+}
+
+/// One bit per IDT vector. The CPU exceptions, the PIC vectors, the fixed IPI vectors
+/// `0xfc..0xff` of `smp` and the spurious vector 0xff are taken from the start.
+static VECTORS: spin::Mutex<[u64; 4]> =
+    spin::Mutex::new([0x0000_ffff_ffff_ffff, 0, 0, 0xf << 60]);
+
+/// Hands out a free vector of the given priority class and installs `handler` at it, or
+/// returns `None` if the class is used up.
+pub fn alloc_vector(class: PriorityClass, handler: HandlerFunc) -> Option<u8> {
+    without_interrupts(|| {
+        let mut vectors = VECTORS.lock();
+        let vector = class
+            .range()
+            .find(|&vector| vectors[vector / 64] & (1 << (vector % 64)) == 0)?;
+        vectors[vector / 64] |= 1 << (vector % 64);
+        IDT.lock()[vector].set_handler_fn(handler);
+        Some(vector as u8)
+    })
+}
+
+/// Claims a specific vector, e.g. for a fixed legacy assignment, and installs `handler` at
+/// it. Returns false if the vector is already allocated.
+pub fn claim_vector(vector: u8, handler: HandlerFunc) -> bool {
+    let vector = usize::from(vector);
+    without_interrupts(|| {
+        let mut vectors = VECTORS.lock();
+        if vectors[vector / 64] & (1 << (vector % 64)) != 0 {
+            return false;
+        }
+        vectors[vector / 64] |= 1 << (vector % 64);
+        IDT.lock()[vector].set_handler_fn(handler);
+        true
+    })
+}
+
+/// Returns a vector obtained from `alloc_vector` or `claim_vector` and removes its handler.
+/// Whatever raised the vector must be stopped first.
+pub fn free_vector(vector: u8) {
+    let vector = usize::from(vector);
+    without_interrupts(|| {
+        let mut vectors = VECTORS.lock();
+        assert!(
+            vectors[vector / 64] & (1 << (vector % 64)) != 0,
+            "freeing unallocated vector {:#x}",
+            vector
+        );
+        IDT.lock()[vector] = Entry::missing();
+        vectors[vector / 64] &= !(1 << (vector % 64));
+    })
+}
+
+/// Timer ticks between two `thermal::update`s, about a second at the PIT's default rate.
+const THERMAL_SAMPLE_TICKS: usize = 16;
+
+/// Number of timer interrupts since boot.
+static TICKS: Counter = Counter::new("interrupts.timer_ticks");
+
 lazy_static! {
-    static ref IDT: InterruptDescriptorTable = {
+    /// Behind a lock so that `alloc_vector` and `claim_vector` can install handlers while it
+    /// is loaded. They only touch vectors nothing raises yet, so the CPU never sees a half
+    /// written gate.
+    static ref IDT: spin::Mutex<InterruptDescriptorTable> = spin::Mutex::new({
         let mut idt = InterruptDescriptorTable::new();
         idt.breakpoint.set_handler_fn(breakpoint_handler);
         idt.double_fault.set_handler_fn(double_fault_handler);
         idt.page_fault.set_handler_fn(page_fault_handler);
+        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
         unsafe {
             idt.double_fault.set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
         idt[usize::from(TIMER_INTERRUPT_ID)].set_handler_fn(timer_interrupt_handler);
         idt[usize::from(KEYBOARD_INTERRUPT_ID)].set_handler_fn(keyboard_interrupt_handler);
+        idt[usize::from(smp::TLB_SHOOTDOWN_VECTOR)].set_handler_fn(tlb_shootdown_handler);
+        idt[usize::from(smp::RESCHEDULE_VECTOR)].set_handler_fn(reschedule_handler);
+        idt[usize::from(smp::PANIC_STOP_VECTOR)].set_handler_fn(panic_stop_handler);
+        idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(spurious_interrupt_handler);
         idt
-    };
+    });
 }
 
 pub fn init_idt() {
-    IDT.load();
+    let idt: *const InterruptDescriptorTable = &*IDT.lock();
+    // the table is inside a static, so it stays where it is loaded after the guard is dropped
+    unsafe { &*idt }.load();
+    stats::register(Stat::Counter(&TICKS));
+}
+
+/// Prints every present gate of the loaded IDT: vector, handler address, code selector,
+/// DPL and IST index (0 meaning no stack switch).
+pub fn dump_idt() {
+    use x86_64::structures::DescriptorTablePointer;
+
+    let mut idtr = DescriptorTablePointer { limit: 0, base: 0 };
+    unsafe { asm!("sidt ($0)" :: "r"(&mut idtr) : "memory") };
+
+    let count = (usize::from(idtr.limit) + 1) / 16;
+    for vector in 0..count {
+        let gate = unsafe { &*((idtr.base as usize + vector * 16) as *const [u64; 2]) };
+        let options = (gate[0] >> 32) as u16;
+        if options & (1 << 15) == 0 {
+            continue;
+        }
+
+        let handler =
+            (gate[0] & 0xffff) | ((gate[0] >> 48) << 16) | ((gate[1] & 0xffff_ffff) << 32);
+        println!(
+            "{:#04x}: handler {}, selector {:#x}, dpl {}, ist {}",
+            vector,
+            symbols::Address(handler as usize),
+            (gate[0] >> 16) as u16,
+            (options >> 13) & 0b11,
+            options & 0b111
+        );
+    }
+}
+
+/// Returns the number of timer interrupts since boot.
+pub fn ticks() -> usize {
+    TICKS.get()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelfTestError {
+    /// No interrupt arrived at `TIMER_INTERRUPT_ID`, so the PIC offsets are likely wrong.
+    TimerSilent,
+    /// The PS/2 controller status port reads as floating, so there is no keyboard controller.
+    NoKeyboardController,
+}
+
+/// Each iteration of the timer wait writes to the POST port, which takes about a
+/// microsecond. This gives a time base that doesn't depend on the timer under test.
+const TIMER_WAIT_ITERATIONS: usize = 100_000;
+
+/// Checks that the legacy IRQs arrive where `init_idt` expects them.
+///
+/// Briefly programs the PIT to 1 kHz and waits for a timer interrupt, then checks that a
+/// keyboard controller is present. Must run after the PICs are initialized and interrupts
+/// are enabled. Prints each failure and returns the first one.
+pub fn self_test() -> Result<(), SelfTestError> {
+    let mut result = Ok(());
+
+    pit::set_frequency(1000);
+    let start = ticks();
+    let fired = (0..TIMER_WAIT_ITERATIONS).any(|_| {
+        progress::io_delay();
+        ticks() != start
+    });
+    pit::reset();
+
+    if !fired {
+        println!("IRQ SELF-TEST FAILED: no timer interrupt at vector {}", TIMER_INTERRUPT_ID);
+        result = Err(SelfTestError::TimerSilent);
+    }
+
+    if unsafe { keyboard::status_port().read() } == 0xff {
+        println!("IRQ SELF-TEST FAILED: no keyboard controller at port 0x64");
+        result = result.and(Err(SelfTestError::NoKeyboardController));
+    }
+
+    result
 }
 
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut ExceptionStackFrame) {
@@ -44,56 +227,100 @@ extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut ExceptionStackFra
 
 extern "x86-interrupt" fn page_fault_handler(
     stack_frame: &mut ExceptionStackFrame,
-    _error_code: PageFaultErrorCode,
+    error_code: PageFaultErrorCode,
 ) {
-    use crate::hlt_loop;
     use x86_64::registers::control::Cr2;
 
+    let addr = Cr2::read();
+    let write_to_present =
+        PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if error_code.contains(write_to_present) && memory::handle_copy_on_write_fault(addr) {
+        return;
+    }
+    if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+        && memory::handle_demand_fault(addr)
+    {
+        return;
+    }
+
     println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
+    println!("Accessed Address: {:?}", addr);
     println!("{:#?}", stack_frame);
-    hlt_loop();
+    // the tables on the way to the address, to see which level is missing or wrong
+    memory::dump_page_table(1, addr..addr + 1u64);
+    idle::halt();
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: &mut ExceptionStackFrame,
+    error_code: u64,
+) {
+    if msr::recover_probe(stack_frame) {
+        return;
+    }
+
+    println!("EXCEPTION: GENERAL PROTECTION FAULT");
+    println!("Error Code: {:#x}", error_code);
+    println!("{:#?}", stack_frame);
+    idle::halt();
 }
 
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: &mut ExceptionStackFrame, _error_code: u64)
 {
     println!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
-    hlt_loop();
+    idle::halt();
 }
 
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: &mut ExceptionStackFrame) {
+    trace::irq_enter(TIMER_INTERRUPT_ID);
+    TICKS.inc();
     print!(".");
+    if TICKS.get() % THERMAL_SAMPLE_TICKS == 0 {
+        // MSR reads are slow, keep them out of interrupt context
+        idle::defer(thermal::update);
+    }
 
     unsafe {
         PICS.lock()
             .notify_end_of_interrupt(TIMER_INTERRUPT_ID);
     }
+    trace::irq_exit(TIMER_INTERRUPT_ID);
 }
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut ExceptionStackFrame) {
-    use pc_keyboard::{layouts, DecodedKey, Keyboard, ScancodeSet1};
-    use spin::Mutex;
-    use x86_64::instructions::port::Port;
+    use crate::input::{self, InputEvent};
 
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1));
+    trace::irq_enter(KEYBOARD_INTERRUPT_ID);
+    let scancode = unsafe { keyboard::data_port().read() };
+    if let Some(key) = keyboard::add_scancode(scancode) {
+        input::publish(InputEvent::Key(key));
     }
 
-    let mut keyboard = KEYBOARD.lock();
-    let port = Port::new(0x60);
+    unsafe { PICS.lock().notify_end_of_interrupt(KEYBOARD_INTERRUPT_ID) }
+    trace::irq_exit(KEYBOARD_INTERRUPT_ID);
+}
+
+extern "x86-interrupt" fn tlb_shootdown_handler(_stack_frame: &mut ExceptionStackFrame) {
+    x86_64::instructions::tlb::flush_all();
+    apic::end_of_interrupt();
+}
 
-    let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character) => print!("{}", character),
-                DecodedKey::RawKey(key) => print!("{:?}", key),
-            }
-        }
-    }
+extern "x86-interrupt" fn reschedule_handler(_stack_frame: &mut ExceptionStackFrame) {
+    // there is no scheduler yet, the interrupt itself ends any `hlt`
+    apic::end_of_interrupt();
+}
 
-    unsafe { PICS.lock().notify_end_of_interrupt(KEYBOARD_INTERRUPT_ID) }
+extern "x86-interrupt" fn panic_stop_handler(stack_frame: &mut ExceptionStackFrame) {
+    serial_println!(
+        "cpu {} stopped at {:?}, stack pointer {:?}",
+        smp::current_cpu(),
+        stack_frame.instruction_pointer,
+        stack_frame.stack_pointer
+    );
+    idle::halt();
 }
 
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: &mut ExceptionStackFrame) {
+    // spurious interrupts must not be acknowledged
+}
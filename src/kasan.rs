@@ -0,0 +1,183 @@
+use core::mem::size_of;
+use core::ptr;
+
+/// Heap bytes described by one shadow byte.
+pub const GRANULE: usize = 8;
+
+/// Shadow memory for the hole list heap, with the `kasan` feature.
+///
+/// A shadow byte of 0 means the whole granule is allocated, 1 to 7 that only that many
+/// leading bytes are, and 0xff that none are. Allocations past a page live outside the
+/// hole list and are not checked.
+#[cfg(feature = "kasan")]
+mod shadow {
+    use super::GRANULE;
+    use crate::hole::align_up;
+    use spin::Mutex;
+    use x86_64::instructions::interrupts::without_interrupts;
+
+    /// Largest heap the shadow covers. Bytes past it are not checked.
+    const MAX_HEAP_SIZE: usize = 1024 * 1024;
+    const POISONED: u8 = 0xff;
+
+    struct Shadow {
+        bottom: usize,
+        size: usize,
+        bytes: [u8; MAX_HEAP_SIZE / GRANULE],
+    }
+
+    static SHADOW: Mutex<Shadow> = Mutex::new(Shadow {
+        bottom: 0,
+        size: 0,
+        bytes: [0; MAX_HEAP_SIZE / GRANULE],
+    });
+
+    impl Shadow {
+        fn index(&self, addr: usize) -> Option<usize> {
+            if addr < self.bottom || addr >= self.bottom + self.size {
+                return None;
+            }
+            Some((addr - self.bottom) / GRANULE)
+        }
+
+        /// `addr` must be granule aligned, which `round_size` guarantees for allocations.
+        fn fill(&mut self, addr: usize, size: usize, poisoned: bool) {
+            let end = addr + size;
+            let mut granule = addr;
+            while granule < end {
+                if let Some(index) = self.index(granule) {
+                    self.bytes[index] = match end - granule {
+                        _ if poisoned => POISONED,
+                        rest if rest >= GRANULE => 0,
+                        rest => rest as u8,
+                    };
+                }
+                granule += GRANULE;
+            }
+        }
+
+        fn is_allocated(&self, addr: usize) -> bool {
+            self.index(addr).is_some() && self.is_addressable(addr)
+        }
+
+        fn is_addressable(&self, addr: usize) -> bool {
+            match self.index(addr) {
+                None => true,
+                Some(index) => match self.bytes[index] {
+                    0 => true,
+                    POISONED => false,
+                    valid => (addr - self.bottom) % GRANULE < valid as usize,
+                },
+            }
+        }
+    }
+
+    pub fn init(bottom: usize, size: usize) {
+        without_interrupts(|| {
+            let mut shadow = SHADOW.lock();
+            shadow.bottom = bottom;
+            shadow.size = size.min(MAX_HEAP_SIZE);
+            let covered = shadow.size;
+            shadow.fill(bottom, covered, true);
+        });
+    }
+
+    pub fn poison(addr: usize, size: usize) {
+        without_interrupts(|| SHADOW.lock().fill(addr, size, true));
+    }
+
+    /// Covers `[top, top + size)` as well, poisoned, where the heap has grown.
+    pub fn extend(top: usize, size: usize) {
+        without_interrupts(|| {
+            let mut shadow = SHADOW.lock();
+            let covered = (top + size - shadow.bottom).min(MAX_HEAP_SIZE);
+            shadow.size = covered;
+            shadow.fill(top, size, true);
+        });
+    }
+
+    pub fn unpoison(addr: usize, size: usize) {
+        without_interrupts(|| SHADOW.lock().fill(addr, size, false));
+    }
+
+    pub fn round_size(size: usize) -> usize {
+        align_up(size, GRANULE)
+    }
+
+    /// Panics unless all of `[addr, addr + size)` is allocated.
+    pub fn check(addr: usize, size: usize, access: &str) {
+        let invalid = without_interrupts(|| {
+            let shadow = SHADOW.lock();
+            (addr..addr + size).find(|&byte| !shadow.is_addressable(byte))
+        });
+        if let Some(byte) = invalid {
+            panic!(
+                "kasan: invalid {} of {} bytes at {:#x}, {:#x} is not allocated",
+                access, size, addr, byte
+            );
+        }
+    }
+
+    /// Panics if any of `[addr, addr + size)` is allocated.
+    pub fn check_free(addr: usize, size: usize, access: &str) {
+        let allocated = without_interrupts(|| {
+            let shadow = SHADOW.lock();
+            (addr..addr + size).find(|&byte| shadow.is_allocated(byte))
+        });
+        if let Some(byte) = allocated {
+            panic!(
+                "kasan: {} of {} bytes of free memory at {:#x}, {:#x} is allocated",
+                access, size, addr, byte
+            );
+        }
+    }
+}
+
+#[cfg(not(feature = "kasan"))]
+mod shadow {
+    pub fn init(_bottom: usize, _size: usize) {}
+
+    pub fn poison(_addr: usize, _size: usize) {}
+
+    pub fn extend(_top: usize, _size: usize) {}
+
+    pub fn unpoison(_addr: usize, _size: usize) {}
+
+    pub fn round_size(size: usize) -> usize {
+        size
+    }
+
+    pub fn check(_addr: usize, _size: usize, _access: &str) {}
+
+    pub fn check_free(_addr: usize, _size: usize, _access: &str) {}
+}
+
+pub use self::shadow::check;
+pub(crate) use self::shadow::{check_free, extend, init, poison, round_size, unpoison};
+
+/// Reads `*ptr`, panicking first if the heap memory it points to is not allocated.
+///
+/// Without the `kasan` feature this is `ptr::read`.
+pub unsafe fn load<T: Copy>(ptr: *const T) -> T {
+    check(ptr as usize, size_of::<T>(), "load");
+    ptr::read(ptr)
+}
+
+/// Writes `value` to `*ptr`, panicking first if the heap memory it points to is not
+/// allocated.
+///
+/// Without the `kasan` feature this is `ptr::write`.
+pub unsafe fn store<T>(ptr: *mut T, value: T) {
+    check(ptr as usize, size_of::<T>(), "store");
+    ptr::write(ptr, value)
+}
+
+/// Writes `value` to `*ptr` in free heap memory, for the allocator's own bookkeeping like
+/// the headers of holes. Panics first if the memory is allocated, since then the free list
+/// is about to overwrite a live allocation.
+///
+/// Without the `kasan` feature this is `ptr::write`.
+pub unsafe fn store_free<T>(ptr: *mut T, value: T) {
+    check_free(ptr as usize, size_of::<T>(), "store");
+    ptr::write(ptr, value)
+}
@@ -0,0 +1,387 @@
+use crate::portio::PortRange;
+use lazy_static::lazy_static;
+use pc_keyboard::{
+    layouts, DecodedKey, KeyCode, KeyEvent, KeyState, Keyboard, KeyboardLayout, Modifiers,
+    ScancodeSet1,
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+/// The PS/2 controller's data port, which also delivers scancodes.
+pub const DATA_PORT: u16 = 0x60;
+/// The PS/2 controller's status and command port.
+pub const STATUS_PORT: u16 = 0x64;
+
+/// Scancode of the extra ISO key left of Z, which pc-keyboard doesn't decode.
+const ISO_KEY_SCANCODE: u8 = 0x56;
+/// Scancode of Print Screen while Alt is held, which pc-keyboard doesn't decode either.
+const SYSRQ_SCANCODE: u8 = 0x54;
+const RELEASE_BIT: u8 = 0x80;
+
+const COMBINING_GRAVE: char = '\u{300}';
+const COMBINING_ACUTE: char = '\u{301}';
+const COMBINING_CIRCUMFLEX: char = '\u{302}';
+
+/// `(dead key, base, composed)` triples for dead key composition.
+const COMPOSE: &[(char, char, char)] = &[
+    (COMBINING_GRAVE, 'a', 'à'), (COMBINING_GRAVE, 'e', 'è'), (COMBINING_GRAVE, 'i', 'ì'),
+    (COMBINING_GRAVE, 'o', 'ò'), (COMBINING_GRAVE, 'u', 'ù'), (COMBINING_GRAVE, 'A', 'À'),
+    (COMBINING_GRAVE, 'E', 'È'), (COMBINING_GRAVE, 'I', 'Ì'), (COMBINING_GRAVE, 'O', 'Ò'),
+    (COMBINING_GRAVE, 'U', 'Ù'),
+    (COMBINING_ACUTE, 'a', 'á'), (COMBINING_ACUTE, 'e', 'é'), (COMBINING_ACUTE, 'i', 'í'),
+    (COMBINING_ACUTE, 'o', 'ó'), (COMBINING_ACUTE, 'u', 'ú'), (COMBINING_ACUTE, 'y', 'ý'),
+    (COMBINING_ACUTE, 'A', 'Á'), (COMBINING_ACUTE, 'E', 'É'), (COMBINING_ACUTE, 'I', 'Í'),
+    (COMBINING_ACUTE, 'O', 'Ó'), (COMBINING_ACUTE, 'U', 'Ú'), (COMBINING_ACUTE, 'Y', 'Ý'),
+    (COMBINING_CIRCUMFLEX, 'a', 'â'), (COMBINING_CIRCUMFLEX, 'e', 'ê'),
+    (COMBINING_CIRCUMFLEX, 'i', 'î'), (COMBINING_CIRCUMFLEX, 'o', 'ô'),
+    (COMBINING_CIRCUMFLEX, 'u', 'û'), (COMBINING_CIRCUMFLEX, 'A', 'Â'),
+    (COMBINING_CIRCUMFLEX, 'E', 'Ê'), (COMBINING_CIRCUMFLEX, 'I', 'Î'),
+    (COMBINING_CIRCUMFLEX, 'O', 'Ô'), (COMBINING_CIRCUMFLEX, 'U', 'Û'),
+];
+
+/// The keymaps the driver can switch between at runtime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Us,
+    Uk,
+    De,
+}
+
+impl Layout {
+    /// Parses a layout name as typed by the user, e.g. `"de"`.
+    pub fn from_name(name: &str) -> Option<Layout> {
+        match name {
+            "us" => Some(Layout::Us),
+            "uk" => Some(Layout::Uk),
+            "de" => Some(Layout::De),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Layout::Us => "us",
+            Layout::Uk => "uk",
+            Layout::De => "de",
+        }
+    }
+}
+
+/// Decodes scancodes with a runtime selectable keymap.
+///
+/// pc-keyboard only turns scancodes into key events here. Modifiers are tracked by us,
+/// since its `Keyboard` bakes the layout into its type.
+struct KeyboardState {
+    decoder: Keyboard<layouts::Us104Key, ScancodeSet1>,
+    layout: Layout,
+    modifiers: Modifiers,
+    ctrl: bool,
+    dead_key: Option<char>,
+    /// Alt+SysRq is held, keys are commands for `sysrq::handle`.
+    sysrq: bool,
+    /// A SysRq command waiting to be run once the keyboard lock is released.
+    sysrq_command: Option<char>,
+}
+
+lazy_static! {
+    static ref KEYBOARD: Mutex<KeyboardState> = Mutex::new(KeyboardState {
+        decoder: Keyboard::new(layouts::Us104Key, ScancodeSet1),
+        layout: Layout::Us,
+        modifiers: Modifiers {
+            lshift: false,
+            rshift: false,
+            numlock: true,
+            capslock: false,
+            alt_gr: false,
+        },
+        ctrl: false,
+        dead_key: None,
+        sysrq: false,
+        sysrq_command: None,
+    });
+}
+
+impl KeyboardState {
+    fn add_scancode(&mut self, scancode: u8) -> Option<DecodedKey> {
+        if scancode & !RELEASE_BIT == SYSRQ_SCANCODE {
+            self.sysrq = scancode & RELEASE_BIT == 0;
+            return None;
+        }
+
+        let event = if scancode & !RELEASE_BIT == ISO_KEY_SCANCODE {
+            let state = if scancode & RELEASE_BIT == 0 {
+                KeyState::Down
+            } else {
+                KeyState::Up
+            };
+            KeyEvent::new(KeyCode::BackSlash, state)
+        } else {
+            let mut event = self.decoder.add_byte(scancode).ok()??;
+            // on ISO keyboards the key that set 1 calls backslash sits next to Enter
+            if event.code == KeyCode::BackSlash && self.layout != Layout::Us {
+                event.code = KeyCode::HashTilde;
+            }
+            event
+        };
+
+        let down = event.state == KeyState::Down;
+        match event.code {
+            KeyCode::ShiftLeft => self.modifiers.lshift = down,
+            KeyCode::ShiftRight => self.modifiers.rshift = down,
+            KeyCode::AltRight => self.modifiers.alt_gr = down,
+            KeyCode::ControlLeft | KeyCode::ControlRight => self.ctrl = down,
+            KeyCode::CapsLock if down => self.modifiers.capslock = !self.modifiers.capslock,
+            KeyCode::NumpadLock if down => self.modifiers.numlock = !self.modifiers.numlock,
+            code if down && self.sysrq => {
+                // the key's character on the US layout names the command on every layout
+                let key = layouts::Us104Key::map_keycode(code, &self.modifiers);
+                if let DecodedKey::Unicode(c) = key {
+                    self.sysrq_command = Some(c.to_ascii_lowercase());
+                }
+            }
+            code if down => return self.translate(code),
+            _ => {}
+        }
+        None
+    }
+
+    fn translate(&mut self, code: KeyCode) -> Option<DecodedKey> {
+        let key = match self.layout {
+            Layout::Us => layouts::Us104Key::map_keycode(code, &self.modifiers),
+            Layout::Uk => layouts::Uk105Key::map_keycode(code, &self.modifiers),
+            Layout::De => De105Key::map_keycode(code, &self.modifiers),
+        };
+
+        let c = match key {
+            DecodedKey::Unicode(c) => c,
+            raw => return Some(raw),
+        };
+        // Ctrl with a letter gives the ASCII control character, e.g. U+0003 for Ctrl+C
+        if self.ctrl && c.is_ascii_alphabetic() {
+            self.dead_key = None;
+            return Some(DecodedKey::Unicode((c as u8 & 0x1f) as char));
+        }
+        match self.dead_key.take() {
+            None if is_dead_key(c) => {
+                self.dead_key = Some(c);
+                None
+            }
+            None => Some(DecodedKey::Unicode(c)),
+            // pressing space or the dead key again gives the accent itself
+            Some(dead) if c == ' ' || c == dead => Some(DecodedKey::Unicode(spacing_accent(dead))),
+            Some(dead) => {
+                let composed = COMPOSE
+                    .iter()
+                    .find(|&&(accent, base, _)| accent == dead && base == c)
+                    .map_or(c, |&(_, _, composed)| composed);
+                Some(DecodedKey::Unicode(composed))
+            }
+        }
+    }
+}
+
+fn is_dead_key(c: char) -> bool {
+    c == COMBINING_GRAVE || c == COMBINING_ACUTE || c == COMBINING_CIRCUMFLEX
+}
+
+fn spacing_accent(dead: char) -> char {
+    match dead {
+        COMBINING_GRAVE => '`',
+        COMBINING_ACUTE => '´',
+        _ => '^',
+    }
+}
+
+crate::kernel_driver!(PS2_DRIVER, "ps2", claim_ports);
+
+lazy_static! {
+    // 0x61 in between belongs to the PC speaker and NMI control, not to the controller
+    static ref DATA_PORTS: PortRange = PortRange::claim(DATA_PORT..=DATA_PORT, "ps2");
+    static ref STATUS_PORTS: PortRange = PortRange::claim(STATUS_PORT..=STATUS_PORT, "ps2");
+}
+
+fn claim_ports() -> Result<(), &'static str> {
+    lazy_static::initialize(&DATA_PORTS);
+    lazy_static::initialize(&STATUS_PORTS);
+    Ok(())
+}
+
+/// The controller's data port, through the driver's claim.
+pub fn data_port() -> Port<u8> {
+    DATA_PORTS.port(DATA_PORT)
+}
+
+/// The controller's status port, which takes controller commands when written, through the
+/// driver's claim.
+pub fn status_port() -> Port<u8> {
+    STATUS_PORTS.port(STATUS_PORT)
+}
+
+/// Feeds a byte read from the PS/2 data port. Returns a key once one is complete.
+///
+/// Keys pressed while Alt+SysRq is held are not returned but run as `sysrq` commands,
+/// right here in the caller, which is the keyboard interrupt.
+pub fn add_scancode(scancode: u8) -> Option<DecodedKey> {
+    let (key, sysrq_command) = interrupts::without_interrupts(|| {
+        let mut keyboard = KEYBOARD.lock();
+        let key = keyboard.add_scancode(scancode);
+        (key, keyboard.sysrq_command.take())
+    });
+    if let Some(command) = sysrq_command {
+        crate::sysrq::handle(command);
+    }
+    key
+}
+
+pub fn layout() -> Layout {
+    interrupts::without_interrupts(|| KEYBOARD.lock().layout)
+}
+
+/// Switches the keymap used for all following key presses.
+pub fn set_layout(layout: Layout) {
+    interrupts::without_interrupts(|| {
+        let mut keyboard = KEYBOARD.lock();
+        keyboard.layout = layout;
+        keyboard.dead_key = None;
+    });
+}
+
+/// A German 105-key QWERTZ keyboard.
+///
+/// Circumflex, acute and grave are dead keys, reported as the combining characters
+/// U+0302, U+0301 and U+0300. AltGr gives the third level, e.g. `@` on Q.
+pub struct De105Key;
+
+impl KeyboardLayout for De105Key {
+    fn map_keycode(keycode: KeyCode, modifiers: &Modifiers) -> DecodedKey {
+        let m = modifiers;
+        let c = match keycode {
+            KeyCode::BackTick => symbol(m, COMBINING_CIRCUMFLEX, '°', None),
+            KeyCode::Key1 => symbol(m, '1', '!', None),
+            KeyCode::Key2 => symbol(m, '2', '"', Some('²')),
+            KeyCode::Key3 => symbol(m, '3', '§', Some('³')),
+            KeyCode::Key4 => symbol(m, '4', '$', None),
+            KeyCode::Key5 => symbol(m, '5', '%', None),
+            KeyCode::Key6 => symbol(m, '6', '&', None),
+            KeyCode::Key7 => symbol(m, '7', '/', Some('{')),
+            KeyCode::Key8 => symbol(m, '8', '(', Some('[')),
+            KeyCode::Key9 => symbol(m, '9', ')', Some(']')),
+            KeyCode::Key0 => symbol(m, '0', '=', Some('}')),
+            KeyCode::Minus => symbol(m, 'ß', '?', Some('\\')),
+            KeyCode::Equals => symbol(m, COMBINING_ACUTE, COMBINING_GRAVE, None),
+            KeyCode::Q if m.alt_gr => '@',
+            KeyCode::E if m.alt_gr => '€',
+            KeyCode::M if m.alt_gr => 'µ',
+            KeyCode::Y => letter(m, 'z', 'Z'),
+            KeyCode::Z => letter(m, 'y', 'Y'),
+            KeyCode::BracketSquareLeft => letter(m, 'ü', 'Ü'),
+            KeyCode::BracketSquareRight => symbol(m, '+', '*', Some('~')),
+            KeyCode::SemiColon => letter(m, 'ö', 'Ö'),
+            KeyCode::Quote => letter(m, 'ä', 'Ä'),
+            KeyCode::HashTilde => symbol(m, '#', '\'', None),
+            KeyCode::BackSlash => symbol(m, '<', '>', Some('|')),
+            KeyCode::Comma => symbol(m, ',', ';', None),
+            KeyCode::Fullstop => symbol(m, '.', ':', None),
+            KeyCode::Slash => symbol(m, '-', '_', None),
+            other => return layouts::Us104Key::map_keycode(other, modifiers),
+        };
+        DecodedKey::Unicode(c)
+    }
+}
+
+/// Picks the level of a letter key, which caps lock affects.
+fn letter(modifiers: &Modifiers, lower: char, upper: char) -> char {
+    if modifiers.is_shifted() {
+        upper
+    } else {
+        lower
+    }
+}
+
+/// Picks the level of a symbol key, which caps lock doesn't affect.
+fn symbol(modifiers: &Modifiers, normal: char, shifted: char, alt_gr: Option<char>) -> char {
+    match alt_gr {
+        Some(c) if modifiers.alt_gr => c,
+        _ if modifiers.lshift || modifiers.rshift => shifted,
+        _ => normal,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn construct_keyboard(layout: Layout) -> KeyboardState {
+        KeyboardState {
+            decoder: Keyboard::new(layouts::Us104Key, ScancodeSet1),
+            layout,
+            modifiers: Modifiers {
+                lshift: false,
+                rshift: false,
+                numlock: true,
+                capslock: false,
+                alt_gr: false,
+            },
+            ctrl: false,
+            dead_key: None,
+            sysrq: false,
+            sysrq_command: None,
+        }
+    }
+
+    #[test]
+    fn de_swaps_y_and_z() {
+        let mut keyboard = construct_keyboard(Layout::De);
+        assert_eq!(keyboard.add_scancode(0x15), Some(DecodedKey::Unicode('z')));
+        assert_eq!(keyboard.add_scancode(0x2c), Some(DecodedKey::Unicode('y')));
+    }
+
+    #[test]
+    fn de_dead_key_composes() {
+        let mut keyboard = construct_keyboard(Layout::De);
+        // acute dead key, then e
+        assert_eq!(keyboard.add_scancode(0x0d), None);
+        assert_eq!(keyboard.add_scancode(0x8d), None);
+        assert_eq!(keyboard.add_scancode(0x12), Some(DecodedKey::Unicode('é')));
+        // acute dead key, then space
+        assert_eq!(keyboard.add_scancode(0x0d), None);
+        assert_eq!(keyboard.add_scancode(0x39), Some(DecodedKey::Unicode('´')));
+    }
+
+    #[test]
+    fn de_alt_gr() {
+        let mut keyboard = construct_keyboard(Layout::De);
+        // right alt is E0 38
+        assert_eq!(keyboard.add_scancode(0xe0), None);
+        assert_eq!(keyboard.add_scancode(0x38), None);
+        assert_eq!(keyboard.add_scancode(0x10), Some(DecodedKey::Unicode('@')));
+    }
+
+    #[test]
+    fn ctrl_gives_control_characters() {
+        let mut keyboard = construct_keyboard(Layout::Us);
+        assert_eq!(keyboard.add_scancode(0x1d), None);
+        assert_eq!(keyboard.add_scancode(0x2e), Some(DecodedKey::Unicode('\u{3}')));
+        assert_eq!(keyboard.add_scancode(0x9d), None);
+        assert_eq!(keyboard.add_scancode(0x2e), Some(DecodedKey::Unicode('c')));
+    }
+
+    #[test]
+    fn sysrq_keys_become_commands() {
+        let mut keyboard = construct_keyboard(Layout::De);
+        assert_eq!(keyboard.add_scancode(0x54), None);
+        // the key labelled Z on a German keyboard is Y on a US one
+        assert_eq!(keyboard.add_scancode(0x15), None);
+        assert_eq!(keyboard.sysrq_command, Some('y'));
+        assert_eq!(keyboard.add_scancode(0xd4), None);
+        assert_eq!(keyboard.add_scancode(0x15), Some(DecodedKey::Unicode('z')));
+    }
+
+    #[test]
+    fn iso_key() {
+        let mut keyboard = construct_keyboard(Layout::Uk);
+        assert_eq!(keyboard.add_scancode(0x56), Some(DecodedKey::Unicode('\\')));
+        assert_eq!(keyboard.add_scancode(0x2b), Some(DecodedKey::Unicode('#')));
+    }
+}
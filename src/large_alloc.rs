@@ -0,0 +1,117 @@
+use core::ptr::NonNull;
+
+pub const PAGE_SIZE: usize = 4096;
+
+/// Number of pages in the virtual region reserved for large allocations (16 MiB).
+const REGION_PAGES: usize = 4096;
+
+/// Maps and unmaps the pages backing large allocations.
+///
+/// Implementations must not allocate from the heap, since they are called with the
+/// heap locked.
+pub trait PageProvider: Send {
+    /// Maps `count` pages starting at `addr` to fresh frames. Returns false if that was not
+    /// possible, in which case nothing is left mapped.
+    fn map_pages(&mut self, addr: usize, count: usize) -> bool;
+
+    /// Unmaps `count` pages starting at `addr`.
+    fn unmap_pages(&mut self, addr: usize, count: usize);
+}
+
+/// Serves allocations above a page directly with whole mapped pages, so they don't carve up
+/// the hole list.
+///
+/// The pages live in a dedicated virtual region, so `dealloc` can tell the two kinds of
+/// allocation apart by address alone. A bitmap tracks which pages of the region are taken.
+pub struct LargeAllocator {
+    start: usize,
+    used: [u64; REGION_PAGES / 64],
+    provider: Option<&'static mut dyn PageProvider>,
+}
+
+impl LargeAllocator {
+    /// Creates a `LargeAllocator` without a region. All allocations fail until `init`.
+    pub const fn empty() -> LargeAllocator {
+        LargeAllocator {
+            start: 0,
+            used: [0; REGION_PAGES / 64],
+            provider: None,
+        }
+    }
+
+    /// Claims the virtual region `[start, start + 16 MiB)` for large allocations.
+    ///
+    /// # Unsafe
+    /// The region must be page aligned and unused by anything else.
+    pub unsafe fn init(&mut self, start: usize, provider: &'static mut dyn PageProvider) {
+        assert_eq!(start % PAGE_SIZE, 0);
+
+        self.start = start;
+        self.used = [0; REGION_PAGES / 64];
+        self.provider = Some(provider);
+    }
+
+    /// Returns whether `addr` points into the large allocation region.
+    pub fn contains(&self, addr: usize) -> bool {
+        self.provider.is_some()
+            && addr >= self.start
+            && addr < self.start + REGION_PAGES * PAGE_SIZE
+    }
+
+    pub fn alloc(&mut self, size: usize) -> Option<NonNull<u8>> {
+        let count = pages_for(size);
+        let first = self.find_free(count)?;
+        let addr = self.start + first * PAGE_SIZE;
+
+        if !self.provider.as_mut()?.map_pages(addr, count) {
+            return None;
+        }
+        self.mark(first, count, true);
+        NonNull::new(addr as *mut u8)
+    }
+
+    pub unsafe fn dealloc(&mut self, ptr: NonNull<u8>, size: usize) {
+        let addr = ptr.as_ptr() as usize;
+        let count = pages_for(size);
+
+        assert_eq!(addr % PAGE_SIZE, 0, "invalid large deallocation");
+        if let Some(provider) = self.provider.as_mut() {
+            provider.unmap_pages(addr, count);
+        }
+        self.mark((addr - self.start) / PAGE_SIZE, count, false);
+    }
+
+    /// Searches the bitmap for the first run of `count` free pages.
+    fn find_free(&self, count: usize) -> Option<usize> {
+        let mut run = 0;
+        for page in 0..REGION_PAGES {
+            if self.is_used(page) {
+                run = 0;
+            } else {
+                run += 1;
+                if run == count {
+                    return Some(page + 1 - count);
+                }
+            }
+        }
+        None
+    }
+
+    fn is_used(&self, page: usize) -> bool {
+        self.used[page / 64] & (1 << (page % 64)) != 0
+    }
+
+    fn mark(&mut self, first: usize, count: usize, used: bool) {
+        for page in first..first + count {
+            if used {
+                self.used[page / 64] |= 1 << (page % 64);
+            } else {
+                self.used[page / 64] &= !(1 << (page % 64));
+            }
+        }
+    }
+}
+
+fn pages_for(size: usize) -> usize {
+    (size + PAGE_SIZE - 1) / PAGE_SIZE
+}
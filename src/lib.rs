@@ -1,5 +1,6 @@
 #![cfg_attr(not(test), no_std)]
 #![feature(abi_x86_interrupt)]
+#![feature(asm)]
 #![feature(const_fn)]
 #![feature(global_allocator)]
 
@@ -11,6 +12,7 @@
 
 #![feature(lang_items)]
 
+#[macro_use]
 extern crate alloc;
 #[cfg(feature = "use_spin")]
 extern crate spin;
@@ -21,30 +23,80 @@ pub mod gdt;
 pub mod serial;
 pub mod vga_buffer;
 pub mod interrupts;
+pub mod keyboard;
+pub mod input;
+pub mod console;
+pub mod device;
+pub mod driver;
+pub mod portio;
+pub mod pit;
+pub mod apic;
+pub mod smp;
 pub mod memory;
-pub mod hole;
+pub mod pat;
+pub mod msr;
+pub mod cpu;
+pub mod thermal;
+pub mod early_alloc;
+pub use kernel_core::hole;
 pub mod heap_allocator;
+pub mod large_alloc;
+pub mod kasan;
+pub mod block_alloc;
+pub mod progress;
+pub mod config;
+pub mod stats;
+pub mod backtrace;
+pub mod panic;
+pub use kernel_core::compress;
+pub mod crashdump;
+pub use kernel_core::crypto;
+pub mod symbols;
+pub mod sysrq;
+pub mod trace;
+pub mod idle;
+#[cfg(feature = "selftest")]
+pub mod selftest;
 
 use heap_allocator::GlobalHeapAllocator;
 
 
+/// Values written to QEMU's `isa-debug-exit` device; QEMU exits with `(code << 1) | 1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0,
+    Failed = 1,
+}
+
 pub unsafe fn exit_qemu() {
+    exit_qemu_with(QemuExitCode::Success);
+}
+
+pub unsafe fn exit_qemu_with(code: QemuExitCode) {
     use x86_64::instructions::port::Port;
 
-    let mut port = Port::<u32>::new(0xf4);
-    port.write(0);
-}
+    heap_allocator::print_peaks(&HEAP_ALLOCATOR);
 
-pub fn hlt_loop() -> ! {
-    loop {
-        x86_64::instructions::hlt();
-    }
+    let mut port = Port::<u32>::new(0xf4);
+    port.write(code as u32);
 }
 
 // define what happens in an Out Of Memory (OOM) condition
 #[alloc_error_handler]
-fn alloc_error(_layout: Layout) -> ! {
-    loop {}
+fn alloc_error(layout: Layout) -> ! {
+    println!("OUT OF MEMORY allocating {} bytes, aligned to {}", layout.size(), layout.align());
+    match HEAP_ALLOCATOR.try_lock() {
+        Some(heap) => {
+            let largest = heap.largest_free_block();
+            println!("{} bytes free, largest free block {} bytes", heap.free(), largest);
+            if heap.free() >= layout.size() && largest < layout.size() {
+                println!("the heap is too fragmented for this allocation");
+            }
+        }
+        None => println!("heap locked, no statistics"),
+    }
+    idle::halt()
 }
 
 
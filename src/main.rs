@@ -8,33 +8,84 @@ use core::panic::PanicInfo;
 use os_rust::memory;
 #[macro_use]
 extern crate alloc;
+use alloc::boxed::Box;
 entry_point!(kernel_main);
 
-pub const HEAP_START: usize = 0o_000_000_000_000_0000;
 pub const HEAP_SIZE: usize = 1000 * 1024; // 100 KiB
+pub const LARGE_ALLOC_START: usize = 0o_000_001_000_000_0000;
 
 
 #[cfg(not(test))]
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
     use os_rust::interrupts::PICS;
+    use os_rust::progress::{self, Stage};
 
     println!("Hello World{}", "!");
+    if let Err(err) = os_rust::config::apply_args(os_rust::config::cmdline()) {
+        println!("bad kernel arguments {:?}: {:?}", os_rust::config::cmdline(), err);
+    }
 
+    progress::report(Stage::Gdt);
     os_rust::gdt::init();
+    let protections = os_rust::cpu::init();
+    println!("SMEP: {}, SMAP: {}", protections.smep, protections.smap);
+    progress::report(Stage::Idt);
     os_rust::interrupts::init_idt();
+    os_rust::driver::init_all();
+    progress::report(Stage::Pic);
     unsafe { PICS.lock().initialize() };
     x86_64::instructions::interrupts::enable();
+    if os_rust::config::IRQ_SELF_TEST.get() != 0 {
+        let _ = os_rust::interrupts::self_test();
+    }
 
+    progress::report(Stage::Paging);
+    os_rust::pat::init();
     let mut recursive_page_table = unsafe { memory::init(boot_info.p4_table_addr as usize) };
+    let mut frame_allocator = memory::init_frame_allocator(&boot_info.memory_map);
+    memory::map_physical_memory(
+        &mut recursive_page_table,
+        &mut frame_allocator,
+        &boot_info.memory_map,
+    )
+    .expect("mapping physical memory failed");
+    let protected = memory::protect_kernel(&mut recursive_page_table);
+    if protected != 0 {
+        println!("made {} writable kernel pages read-only", protected);
+    }
 
     println!("p4 table address at {:#x}",boot_info.p4_table_addr);
-
-    unsafe{
-        os_rust::HEAP_ALLOCATOR.lock().init(boot_info.p4_table_addr as usize + 0x10, HEAP_SIZE);
+    memory::print_memory_map(boot_info);
+
+    progress::report(Stage::Heap);
+    println!("boot seed {}", os_rust::cpu::boot_seed());
+    let heap_start = memory::random_heap_start();
+    memory::init_heap(&mut recursive_page_table, &mut frame_allocator, heap_start, HEAP_SIZE)
+        .expect("heap initialization failed");
+    println!("heap at {:#x}", memory::heap_start());
+    os_rust::heap_allocator::register_stats();
+    println!("{} kernel symbols", os_rust::symbols::init_embedded());
+
+
+    memory::init_kernel_pages(recursive_page_table, frame_allocator);
+    os_rust::vga_buffer::map_buffer().expect("failed to map the VGA buffer");
+
+    progress::report(Stage::Apic);
+    os_rust::apic::init();
+    os_rust::device::lsdev();
+    let sensors = os_rust::thermal::init();
+    println!("thermal sensor: {}, APERF/MPERF: {}", sensors.temperature, sensors.frequency);
+
+    let double_fault_stack = memory::alloc_stack(os_rust::gdt::DOUBLE_FAULT_STACK_PAGES)
+        .expect("no memory for the double fault stack");
+    unsafe { os_rust::gdt::set_double_fault_stack(double_fault_stack.top()) };
+    unsafe {
+        let mut heap = os_rust::HEAP_ALLOCATOR.lock();
+        heap.init_large(LARGE_ALLOC_START, Box::leak(Box::new(memory::KernelPages)));
+        heap.set_grow(Some(memory::grow_heap));
     }
 
-
-    println!("first hole of the allocator at {:?}", os_rust::HEAP_ALLOCATOR.lock().first_hole());
+    println!("first hole of the allocator at {:?}", os_rust::HEAP_ALLOCATOR.lock().holes().next());
     println!("bottom of the allocator at {:#x}", os_rust::HEAP_ALLOCATOR.lock().bottom());
 
     println!("Test small size layout");
@@ -51,7 +102,7 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
 
     println!("Change of first hole");
-    println!("first hole of the allocator at {:?}", os_rust::HEAP_ALLOCATOR.lock().first_hole());
+    println!("first hole of the allocator at {:?}", os_rust::HEAP_ALLOCATOR.lock().holes().next());
 
 
     println!("Test push into a vector");
@@ -60,14 +111,17 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     }
     println!("{:?}", vec_test);
 
+    #[cfg(feature = "selftest")]
+    os_rust::selftest::run();
+
+    progress::report(Stage::Done);
     println!("It did not crash!");
-    os_rust::hlt_loop();
+    os_rust::idle::run();
 }
 
 /// This function is called on panic.
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{}", info);
-    os_rust::hlt_loop();
+    os_rust::panic::handle(info)
 }
@@ -1,12 +1,85 @@
-use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+pub mod stack_allocator;
+mod address_space;
+mod buddy;
+mod cow;
+mod demand;
+mod reserve;
+mod tlb;
+mod virt_region;
+mod vmalloc;
+
+pub use self::address_space::AddressSpace;
+pub use self::buddy::{BuddyFrameAllocator, HUGE_PAGE_ORDER, MAX_ORDER};
+pub use self::cow::{handle_copy_on_write_fault, mark_copy_on_write, COPY_ON_WRITE};
+pub use self::demand::{alloc_demand_paged, free_demand_paged, handle_demand_fault};
+pub use self::reserve::{reservations, reserve, reserved_by, Reservation};
+pub use self::tlb::{flush_tlb_all, flush_tlb_page, set_tlb_flusher, LocalTlb, TlbFlusher};
+pub use self::vmalloc::{vfree, vmalloc};
+
+use self::stack_allocator::{Stack, StackAllocator};
+use crate::large_alloc::{PageProvider, PAGE_SIZE};
+use crate::msr::{Efer, EferFlags};
+use crate::{cpu, pat, println, serial_println, HEAP_ALLOCATOR};
+use bootloader::bootinfo::{BootInfo, MemoryMap, MemoryRegionType};
 use x86_64::structures::paging::{
-    FrameAllocator, Mapper, Page, PageTable, PhysFrame, RecursivePageTable, Size4KiB,
+    FlagUpdateError, FrameAllocator, FrameDeallocator, MapToError, Mapper, Page, PageTable,
+    PageTableEntry, PageTableFlags, PhysFrame, RecursivePageTable, Size2MiB, Size4KiB, UnmapError,
 };
 
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::registers::control::{Cr0, Cr0Flags};
 use x86_64::{PhysAddr, VirtAddr};
 
 
-/// Creates a RecursivePageTable instance from the level 4 address.
+/// Virtual region `random_heap_start` places the kernel heap in (4 TiB).
+const HEAP_REGION_START: usize = 0x_4000_0000_0000;
+const HEAP_REGION_SIZE: usize = 0x400_0000_0000;
+/// Room above the heap start left for the heap to grow into (1 TiB).
+const HEAP_GROWTH_ROOM: usize = 0x100_0000_0000;
+const HEAP_START_ALIGN: usize = 2 * 1024 * 1024;
+
+/// Virtual region kernel stacks are carved from (256 MiB).
+const STACK_REGION_START: usize = 0x_5555_0000_0000;
+const STACK_REGION_END: usize = STACK_REGION_START + 256 * 1024 * 1024;
+
+/// Where `map_physical_memory` maps all of physical memory, the start of the upper half.
+pub const PHYSICAL_MEMORY_OFFSET: u64 = 0xffff_8000_0000_0000;
+
+/// Index of the level 4 entry through which the bootloader maps the page tables themselves.
+const RECURSIVE_INDEX: u64 = 511;
+
+/// Caching mode of a mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapFlags {
+    WriteBack,
+    WriteThrough,
+    Uncached,
+    /// Buffers writes instead of caching them, for framebuffers. Falls back to uncached
+    /// if the PAT could not be programmed.
+    WriteCombining,
+}
+
+impl MapFlags {
+    /// Returns the level 1 page table flags that select this caching mode.
+    pub fn page_table_flags(self) -> PageTableFlags {
+        match self {
+            MapFlags::WriteBack => PageTableFlags::empty(),
+            MapFlags::WriteThrough => PageTableFlags::WRITE_THROUGH,
+            MapFlags::Uncached => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+            // bit 7 of a level 1 entry is the PAT bit, so PAT + PWT selects PAT entry 5
+            MapFlags::WriteCombining if pat::write_combining_enabled() => {
+                PageTableFlags::HUGE_PAGE | PageTableFlags::WRITE_THROUGH
+            }
+            MapFlags::WriteCombining => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+        }
+    }
+}
+
+/// Creates a RecursivePageTable instance from the level 4 address, and enables
+/// `NO_EXECUTE` in page tables.
 ///
 /// This function is unsafe because it can break memory safety if an invalid
 /// address is passed.
@@ -20,37 +93,426 @@ pub unsafe fn init(level_4_table_addr: usize) -> RecursivePageTable<'static> {
         RecursivePageTable::new(level_4_table).unwrap()
     }
 
+    // the bootloader sets it too, but heap and stack mappings rely on it
+    Efer::update(|flags| *flags |= EferFlags::NO_EXECUTE_ENABLE);
+
     init_inner(level_4_table_addr)
 }
 
+/// Prints every region of the memory map the bootloader passed, with its physical range,
+/// size and type, then the total of usable memory.
+pub fn print_memory_map(boot_info: &BootInfo) {
+    let mut usable = 0;
+    for region in boot_info.memory_map.iter() {
+        let (start, end) = (region.range.start_addr(), region.range.end_addr());
+        println!(
+            "{:#012x}..{:#012x} {:>9} KiB  {}",
+            start,
+            end,
+            (end - start) / 1024,
+            region_type_name(region.region_type)
+        );
+        if region.region_type == MemoryRegionType::Usable {
+            usable += end - start;
+        }
+    }
+    println!("usable: {} KiB", usable / 1024);
+}
+
+fn region_type_name(region_type: MemoryRegionType) -> &'static str {
+    match region_type {
+        MemoryRegionType::Usable => "usable",
+        MemoryRegionType::InUse => "in use",
+        MemoryRegionType::Reserved => "reserved",
+        MemoryRegionType::AcpiReclaimable => "ACPI reclaimable",
+        MemoryRegionType::AcpiNvs => "ACPI NVS",
+        MemoryRegionType::BadMemory => "bad memory",
+        MemoryRegionType::Kernel => "kernel",
+        MemoryRegionType::KernelStack => "kernel stack",
+        MemoryRegionType::PageTable => "page tables",
+        MemoryRegionType::Bootloader => "bootloader",
+        MemoryRegionType::FrameZero => "frame zero",
+        MemoryRegionType::Empty => "empty",
+        MemoryRegionType::BootInfo => "boot info",
+        MemoryRegionType::Package => "package",
+    }
+}
+
 /// Create a FrameAllocator from the passed memory map
-pub fn init_frame_allocator(
-    memory_map: &'static MemoryMap,
-) -> BootInfoFrameAllocator<impl Iterator<Item = PhysFrame>> {
-    // get usable regions from memory map
-    let regions = memory_map
+///
+/// The bootloader marks every frame it uses itself, so the usable regions are free. Only
+/// one allocator may be created from a memory map, or frames are handed out twice.
+pub fn init_frame_allocator(memory_map: &'static MemoryMap) -> BootInfoFrameAllocator {
+    BootInfoFrameAllocator {
+        memory_map,
+        region: 0,
+        next_frame: 0,
+        free_list: None,
+        freed: 0,
+    }
+}
+
+/// Maps every physical frame the memory map knows of at `PHYSICAL_MEMORY_OFFSET`, so that
+/// `phys_to_virt` works. Frames are mapped writable and non-executable, in 2 MiB pages.
+///
+/// The recursive mapping only shows the active page tables; this mapping lets the kernel
+/// read and build the tables of any address space. The bootloader has no option to set it
+/// up, so it is built here, with page tables from `frame_allocator`.
+pub fn map_physical_memory(
+    recursive_page_table: &mut RecursivePageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    memory_map: &MemoryMap,
+) -> Result<(), MapToError> {
+    let end = memory_map
         .iter()
-        .filter(|r| r.region_type == MemoryRegionType::Usable);
-    // map each region to its address range
-    let addr_ranges = regions.map(|r| r.range.start_addr()..r.range.end_addr());
+        .map(|region| region.range.end_frame_number * PAGE_SIZE as u64)
+        .max()
+        .unwrap_or(0);
+    if end == 0 {
+        return Ok(());
+    }
 
-    // transform to an iterator of frame start addresses
-    let frame_addresses = addr_ranges.flat_map(|r| r.into_iter().step_by(4096));
-    // create `PhysFrame` types from the start addresses
-    let frames = frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)));
+    let start_frame = PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(0));
+    let end_frame = PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(end - 1));
+    for frame in PhysFrame::range_inclusive(start_frame, end_frame) {
+        let page = Page::containing_address(VirtAddr::new(
+            PHYSICAL_MEMORY_OFFSET + frame.start_address().as_u64(),
+        ));
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        // every physical frame already has one mapping there, none can alias a page
+        unsafe { map_huge_page(recursive_page_table, page, frame, flags, frame_allocator)? };
+    }
 
-    BootInfoFrameAllocator { frames }
+    PHYSICAL_MEMORY_MAPPED.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Returns where `addr` is mapped by `map_physical_memory`, or `None` before it ran.
+pub fn phys_to_virt(addr: PhysAddr) -> Option<VirtAddr> {
+    if PHYSICAL_MEMORY_MAPPED.load(Ordering::SeqCst) {
+        Some(VirtAddr::new(PHYSICAL_MEMORY_OFFSET + addr.as_u64()))
+    } else {
+        None
+    }
 }
 
 /// Returns the physical address for the given virtual address, or `None` if
 /// the virtual address is not mapped.
-pub fn translate_addr(addr: u64, recursive_page_table: &RecursivePageTable) -> Option<PhysAddr> {
-    let addr = VirtAddr::new(addr);
-    let page: Page = Page::containing_address(addr);
+///
+/// Walks the page tables through the recursive mapping, so no `RecursivePageTable` is
+/// needed. Addresses in 2 MiB and 1 GiB pages are translated too.
+pub fn translate_addr(addr: VirtAddr) -> Option<PhysAddr> {
+    let (entry, offset_mask) = leaf_entry(addr)?;
+    Some(entry.addr() + (addr.as_u64() & offset_mask))
+}
+
+/// Returns the flags of the level 1 entry mapping `page`, or `None` if the page is not
+/// mapped or lies in a 2 MiB or 1 GiB page.
+pub fn page_flags(page: Page) -> Option<PageTableFlags> {
+    match leaf_entry(page.start_address())? {
+        (entry, 0xfff) => Some(entry.flags()),
+        _ => None,
+    }
+}
+
+/// Finds the entry that maps `addr`, along with the mask of the address bits that are an
+/// offset into the mapped page.
+fn leaf_entry(addr: VirtAddr) -> Option<(&'static PageTableEntry, u64)> {
+    let r = RECURSIVE_INDEX;
+    let p4_index = u64::from(addr.p4_index());
+    let p3_index = u64::from(addr.p3_index());
+    let p2_index = u64::from(addr.p2_index());
+
+    // the address of a table is the path to it, shifted up once per recursive hop. Each
+    // table is only looked at once the entry above it is known to point to one.
+    let table = |path: u64| unsafe { &*(VirtAddr::new(path << 12).as_u64() as *const PageTable) };
+
+    let p4 = table(r << 27 | r << 18 | r << 9 | r);
+    let entry = &p4[addr.p4_index()];
+    if !entry.flags().contains(PageTableFlags::PRESENT) {
+        return None;
+    }
+
+    let p3 = table(r << 27 | r << 18 | r << 9 | p4_index);
+    let entry = &p3[addr.p3_index()];
+    if !entry.flags().contains(PageTableFlags::PRESENT) {
+        return None;
+    }
+    if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+        return Some((entry, 0x3fff_ffff));
+    }
+
+    let p2 = table(r << 27 | r << 18 | p4_index << 9 | p3_index);
+    let entry = &p2[addr.p2_index()];
+    if !entry.flags().contains(PageTableFlags::PRESENT) {
+        return None;
+    }
+    if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+        return Some((entry, 0x1f_ffff));
+    }
+
+    let p1 = table(r << 27 | p4_index << 18 | p3_index << 9 | p2_index);
+    let entry = &p1[addr.p1_index()];
+    if !entry.flags().contains(PageTableFlags::PRESENT) {
+        return None;
+    }
+    Some((entry, 0xfff))
+}
+
+/// Prints the present entries of the active page tables that map part of `range`, through
+/// `serial_println!`. Descends down to tables of `level`: 4 prints only the level 4 table,
+/// 1 every level. The recursive entry is printed but not followed.
+pub fn dump_page_table(level: u8, range: Range<VirtAddr>) {
+    assert!(level >= 1 && level <= 4, "page table levels are 1 to 4");
+    dump_table(4, level, &mut [0; 4], 0, &range);
+}
+
+/// Prints the table of `table_level` reached through the first `4 - table_level` of
+/// `indices`, which maps the addresses from `base` on.
+fn dump_table(
+    table_level: u8,
+    lowest_level: u8,
+    indices: &mut [u64; 4],
+    base: u64,
+    range: &Range<VirtAddr>,
+) {
+    const INDENT: &str = "      ";
+
+    let known = 4 - table_level as usize;
+    // the path to a table is filled up with recursive hops from the front
+    let path = (0..4).fold(0, |path, i| {
+        let index = if i < table_level as usize {
+            RECURSIVE_INDEX
+        } else {
+            indices[i - table_level as usize]
+        };
+        path << 9 | index
+    });
+    let table = unsafe { &*(VirtAddr::new(path << 12).as_u64() as *const PageTable) };
+    let entry_size = 1u64 << (12 + 9 * (u64::from(table_level) - 1));
+
+    for index in 0..512 {
+        let entry = &table[index];
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let start = base + index as u64 * entry_size;
+        let first = VirtAddr::new(start).as_u64();
+        let last = first + (entry_size - 1);
+        if first >= range.end.as_u64() || last < range.start.as_u64() {
+            continue;
+        }
+
+        serial_println!(
+            "{}P{}[{:3}] {:#018x} -> {:#x} {:?}",
+            &INDENT[..2 * known],
+            table_level,
+            index,
+            first,
+            entry.addr().as_u64(),
+            flags
+        );
+        let recursive = table_level == 4 && index as u64 == RECURSIVE_INDEX;
+        if table_level > lowest_level && !flags.contains(PageTableFlags::HUGE_PAGE) && !recursive {
+            indices[known] = index as u64;
+            dump_table(table_level - 1, lowest_level, indices, start, range);
+        }
+    }
+}
+
+/// Makes sure the kernel's code and constants can't be written to, and returns how many
+/// pages had to be made read-only.
+///
+/// The bootloader maps each kernel segment with the permissions from its ELF program
+/// header and already sets `CR0.WP`, so this normally finds nothing to fix. It checks
+/// every page from the ELF header to `_etext`, which lld places after the last read-only
+/// segment, so `.rodata` and `.text` stay read-only even if the bootloader changes.
+pub fn protect_kernel(recursive_page_table: &mut RecursivePageTable) -> usize {
+    extern "C" {
+        // both defined by lld
+        static __ehdr_start: u8;
+        static _etext: u8;
+    }
+
+    let start = VirtAddr::new(unsafe { &__ehdr_start } as *const u8 as u64);
+    let end = VirtAddr::new(unsafe { &_etext } as *const u8 as u64);
+    let start_page: Page = Page::containing_address(start);
+    let end_page: Page = Page::containing_address(end - 1u64);
+
+    let mut protected = 0;
+    for page in Page::range_inclusive(start_page, end_page) {
+        // pages between segments are not mapped
+        if let Some(flags) = page_flags(page) {
+            if flags.contains(PageTableFlags::WRITABLE) {
+                update_flags(recursive_page_table, page, flags - PageTableFlags::WRITABLE)
+                    .expect("kernel page vanished while protecting it");
+                protected += 1;
+            }
+        }
+    }
+
+    // without WP, ring 0 ignores the writable bit
+    unsafe { Cr0::update(|cr0| *cr0 |= Cr0Flags::WRITE_PROTECT) };
+    protected
+}
+
+/// Maps `[heap_start, heap_start + heap_size)` to fresh, non-executable frames and hands
+/// that range to the global allocator.
+///
+/// `heap_start` must be page aligned and the range unused. If a mapping fails, the pages
+/// mapped so far are unmapped again and all frames are given back.
+pub fn init_heap<A>(
+    recursive_page_table: &mut RecursivePageTable,
+    frame_allocator: &mut A,
+    heap_start: usize,
+    heap_size: usize,
+) -> Result<(), MapToError>
+where
+    A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>,
+{
+    let start: Page = Page::containing_address(VirtAddr::new(heap_start as u64));
+    let end: Page = Page::containing_address(VirtAddr::new((heap_start + heap_size - 1) as u64));
+
+    for page in Page::range_inclusive(start, end) {
+        let mapped = match frame_allocator.allocate_frame() {
+            Some(frame) => {
+                let mapped = unsafe {
+                    map_page(
+                        recursive_page_table,
+                        page,
+                        frame,
+                        PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+                        frame_allocator,
+                    )
+                };
+                if mapped.is_err() {
+                    frame_allocator.deallocate_frame(frame);
+                }
+                mapped
+            }
+            None => Err(MapToError::FrameAllocationFailed),
+        };
+        if let Err(err) = mapped {
+            for mapped_page in Page::range(start, page) {
+                if let Ok(frame) = unmap_page(recursive_page_table, mapped_page) {
+                    frame_allocator.deallocate_frame(frame);
+                }
+            }
+            return Err(err);
+        }
+    }
+
+    unsafe { HEAP_ALLOCATOR.lock().init(heap_start, heap_size) };
+    HEAP_START.store(heap_start, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Picks a random 2 MiB aligned start for the kernel heap, so its address can't be
+/// guessed. Derived from `cpu::boot_seed`, which is a weak guess where there is no RDRAND.
+pub fn random_heap_start() -> usize {
+    let entropy = cpu::boot_seed();
+    let slots = (HEAP_REGION_SIZE - HEAP_GROWTH_ROOM) / HEAP_START_ALIGN;
+    HEAP_REGION_START + (entropy as usize % slots) * HEAP_START_ALIGN
+}
+
+/// Returns where `init_heap` placed the heap, or 0 before it ran.
+pub fn heap_start() -> usize {
+    HEAP_START.load(Ordering::SeqCst)
+}
+
+/// Maps `page` to `frame` and flushes the TLB entry of `page`. Page tables missing on the
+/// way are allocated from `frame_allocator`. `PRESENT` is always added to `flags`.
+///
+/// This function is unsafe because the caller must make sure that `frame` is not in use
+/// elsewhere, or the mapping aliases that memory.
+pub unsafe fn map_page(
+    recursive_page_table: &mut RecursivePageTable,
+    page: Page,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError> {
+    let flags = flags | PageTableFlags::PRESENT;
+    recursive_page_table
+        .map_to(page, frame, flags, frame_allocator)?
+        .flush();
+    Ok(())
+}
+
+/// Unmaps `page`, flushes its TLB entry and returns the frame it was mapped to.
+///
+/// Page tables that become empty are kept.
+pub fn unmap_page(
+    recursive_page_table: &mut RecursivePageTable,
+    page: Page,
+) -> Result<PhysFrame, UnmapError> {
+    let (frame, flush) = recursive_page_table.unmap(page)?;
+    flush.ignore();
+    flush_tlb_page(page.start_address());
+    Ok(frame)
+}
+
+/// Replaces the flags of the mapped `page`, e.g. to make it read-only or non-executable,
+/// and flushes its TLB entry. `PRESENT` is always added to `flags`.
+pub fn update_flags(
+    recursive_page_table: &mut RecursivePageTable,
+    page: Page,
+    flags: PageTableFlags,
+) -> Result<(), FlagUpdateError> {
+    let flags = flags | PageTableFlags::PRESENT;
+    recursive_page_table.update_flags(page, flags)?.ignore();
+    flush_tlb_page(page.start_address());
+    Ok(())
+}
+
+/// Maps the 2 MiB `page` to `frame` with a single level 2 entry, and flushes its TLB
+/// entry. Page tables missing on the way are allocated from `frame_allocator`. `PRESENT`
+/// is always added to `flags`.
+///
+/// `MapFlags` gives level 1 flags, so `MapFlags::WriteCombining` does not work here: bit 7
+/// is the page size bit at level 2, the PAT bit moves to bit 12.
+///
+/// This function is unsafe because the caller must make sure that `frame` is not in use
+/// elsewhere, or the mapping aliases that memory.
+pub unsafe fn map_huge_page(
+    recursive_page_table: &mut RecursivePageTable,
+    page: Page<Size2MiB>,
+    frame: PhysFrame<Size2MiB>,
+    flags: PageTableFlags,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError> {
+    let flags = flags | PageTableFlags::PRESENT;
+    recursive_page_table
+        .map_to(page, frame, flags, frame_allocator)?
+        .flush();
+    Ok(())
+}
+
+/// Unmaps the 2 MiB `page`, flushes its TLB entry and returns the frame it was mapped to.
+pub fn unmap_huge_page(
+    recursive_page_table: &mut RecursivePageTable,
+    page: Page<Size2MiB>,
+) -> Result<PhysFrame<Size2MiB>, UnmapError> {
+    let (frame, flush) = recursive_page_table.unmap(page)?;
+    flush.ignore();
+    flush_tlb_page(page.start_address());
+    Ok(frame)
+}
 
-    // perform the translation
-    let frame = recursive_page_table.translate_page(page);
-    frame.map(|frame| frame.start_address() + u64::from(addr.page_offset()))
+/// Allows or forbids running code from the mapped `page`, keeping its other flags.
+///
+/// Heap and stack pages are mapped non-executable; this is the way out for code that
+/// generates or loads code into them. Drop `WRITABLE` with `update_flags` once the code is
+/// in place, so no page stays both writable and executable.
+pub fn set_executable(
+    recursive_page_table: &mut RecursivePageTable,
+    page: Page,
+    executable: bool,
+) -> Result<(), FlagUpdateError> {
+    let mut flags = page_flags(page).ok_or(FlagUpdateError::PageNotMapped)?;
+    flags.set(PageTableFlags::NO_EXECUTE, !executable);
+    update_flags(recursive_page_table, page, flags)
 }
 
 pub fn create_example_mapping(
@@ -76,19 +538,256 @@ impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
     }
 }
 
-pub struct BootInfoFrameAllocator<I>
+/// A FrameAllocator that hands out the usable frames of the bootloader's memory map, for
+/// early boot. `init_kernel_pages` replaces it with a `BuddyFrameAllocator`.
+///
+/// Frames are handed out in address order, relying on the memory map being sorted, and
+/// frames overlapping a `reserve`d range are skipped.
+/// Deallocated frames go on a stack that is linked through the frames themselves and taken
+/// from first. That needs `map_physical_memory`; frames deallocated before it are leaked.
+pub struct BootInfoFrameAllocator {
+    memory_map: &'static MemoryMap,
+    /// Index of the region frames are currently taken from.
+    region: usize,
+    /// Number of the next frame to hand out.
+    next_frame: u64,
+    /// The last deallocated frame, whose first eight bytes hold the address of the one
+    /// deallocated before it, or `FREE_LIST_END`.
+    free_list: Option<PhysFrame>,
+    /// Frames on the free list.
+    freed: usize,
+}
+
+/// Marks the end of the free list. Not a frame address, since those are page aligned.
+const FREE_LIST_END: u64 = !0;
+
+impl BootInfoFrameAllocator {
+    /// Returns the number of frames that can still be allocated.
+    pub fn free_frames(&self) -> usize {
+        let untouched: u64 = self
+            .memory_map
+            .iter()
+            .skip(self.region)
+            .filter(|region| region.region_type == MemoryRegionType::Usable)
+            .map(|region| {
+                let start = self.next_frame.max(region.range.start_frame_number);
+                region.range.end_frame_number.saturating_sub(start)
+            })
+            .sum();
+        untouched as usize + self.freed
+    }
+}
+
+impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if let Some(frame) = self.free_list {
+            // only frames reachable through phys_to_virt get on the list
+            let link = phys_to_virt(frame.start_address())?;
+            let next = unsafe { *link.as_ptr::<u64>() };
+            self.free_list = if next == FREE_LIST_END {
+                None
+            } else {
+                Some(PhysFrame::containing_address(PhysAddr::new(next)))
+            };
+            self.freed -= 1;
+            return Some(frame);
+        }
+
+        while let Some(region) = self.memory_map.get(self.region) {
+            if region.region_type == MemoryRegionType::Usable {
+                let frame_number = self.next_frame.max(region.range.start_frame_number);
+                if frame_number < region.range.end_frame_number {
+                    self.next_frame = frame_number + 1;
+                    if reserve::frame_reserved(frame_number) {
+                        continue;
+                    }
+                    let addr = PhysAddr::new(frame_number * PAGE_SIZE as u64);
+                    return Some(PhysFrame::containing_address(addr));
+                }
+            }
+            self.region += 1;
+        }
+        None
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let link = match phys_to_virt(frame.start_address()) {
+            Some(link) => link,
+            None => return,
+        };
+        let next = self.free_list.map_or(FREE_LIST_END, |next| next.start_address().as_u64());
+        unsafe { *link.as_mut_ptr::<u64>() = next };
+        self.free_list = Some(frame);
+        self.freed += 1;
+    }
+}
+
+/// Backs the heap's large allocations with frames from `frame_allocator`, mapped writable and
+/// non-executable. Heap growth and kernel stacks, IST stacks included, go through it too.
+pub struct HeapPages<A> {
+    recursive_page_table: RecursivePageTable<'static>,
+    frame_allocator: A,
+}
+
+impl<A> HeapPages<A>
     where
-        I: Iterator<Item = PhysFrame>,
+        A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB> + Send,
 {
-    frames: I,
+    pub fn new(
+        recursive_page_table: RecursivePageTable<'static>,
+        frame_allocator: A,
+    ) -> HeapPages<A> {
+        HeapPages {
+            recursive_page_table,
+            frame_allocator,
+        }
+    }
 }
 
-impl<I> FrameAllocator<Size4KiB> for BootInfoFrameAllocator<I>
+impl<A> PageProvider for HeapPages<A>
     where
-        I: Iterator<Item = PhysFrame>,
+        A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB> + Send,
 {
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        self.frames.next()
+    fn map_pages(&mut self, addr: usize, count: usize) -> bool {
+        use x86_64::structures::paging::PageTableFlags as Flags;
+
+        for i in 0..count {
+            let page = Page::containing_address(VirtAddr::new((addr + i * PAGE_SIZE) as u64));
+            let frame = match self.frame_allocator.allocate_frame() {
+                Some(frame) => frame,
+                None => {
+                    self.unmap_pages(addr, i);
+                    return false;
+                }
+            };
+
+            let map_result = unsafe {
+                map_page(
+                    &mut self.recursive_page_table,
+                    page,
+                    frame,
+                    Flags::WRITABLE | Flags::NO_EXECUTE,
+                    &mut self.frame_allocator,
+                )
+            };
+            if map_result.is_err() {
+                self.frame_allocator.deallocate_frame(frame);
+                self.unmap_pages(addr, i);
+                return false;
+            }
+        }
+        true
+    }
+
+    fn unmap_pages(&mut self, addr: usize, count: usize) {
+        for i in 0..count {
+            let page: Page = Page::containing_address(VirtAddr::new((addr + i * PAGE_SIZE) as u64));
+            if let Ok(frame) = unmap_page(&mut self.recursive_page_table, page) {
+                self.frame_allocator.deallocate_frame(frame);
+            }
+        }
+    }
+}
+
+static PHYSICAL_MEMORY_MAPPED: AtomicBool = AtomicBool::new(false);
+static HEAP_START: AtomicUsize = AtomicUsize::new(0);
+
+static STACKS: Mutex<StackAllocator> =
+    Mutex::new(StackAllocator::new(STACK_REGION_START, STACK_REGION_END));
+
+static KERNEL_PAGES: Mutex<Option<HeapPages<BuddyFrameAllocator>>> = Mutex::new(None);
+
+/// Hands the page table to `KernelPages`, which shares it between every user of kernel heap
+/// pages, and the frames `frame_allocator` has left to a `BuddyFrameAllocator`.
+///
+/// Needs the heap and `map_physical_memory`.
+pub fn init_kernel_pages(
+    recursive_page_table: RecursivePageTable<'static>,
+    frame_allocator: BootInfoFrameAllocator,
+) {
+    let frame_allocator = BuddyFrameAllocator::new(frame_allocator);
+    without_interrupts(|| {
+        *KERNEL_PAGES.lock() = Some(HeapPages::new(recursive_page_table, frame_allocator));
+    });
+}
+
+/// Runs `f` with the page table and frame allocator handed to `init_kernel_pages`, for
+/// mapping changes after boot. Returns `None` before `init_kernel_pages`.
+///
+/// Runs with interrupts disabled, and `f` must not allocate from the heap, which may need
+/// the page table to grow.
+pub fn with_kernel_pages<F, R>(f: F) -> Option<R>
+where
+    F: FnOnce(&mut RecursivePageTable<'static>, &mut BuddyFrameAllocator) -> R,
+{
+    without_interrupts(|| {
+        KERNEL_PAGES
+            .lock()
+            .as_mut()
+            .map(|pages| f(&mut pages.recursive_page_table, &mut pages.frame_allocator))
+    })
+}
+
+/// A `PageProvider` backed by the page table handed to `init_kernel_pages`. Mapping fails
+/// until then.
+pub struct KernelPages;
+
+impl PageProvider for KernelPages {
+    fn map_pages(&mut self, addr: usize, count: usize) -> bool {
+        without_interrupts(|| match KERNEL_PAGES.lock().as_mut() {
+            Some(pages) => pages.map_pages(addr, count),
+            None => false,
+        })
     }
 
+    fn unmap_pages(&mut self, addr: usize, count: usize) {
+        without_interrupts(|| {
+            if let Some(pages) = KERNEL_PAGES.lock().as_mut() {
+                pages.unmap_pages(addr, count);
+            }
+        });
+    }
+}
+
+/// Maps the page at the physical address of `frame` to `frame`, for device memory like the
+/// VGA buffer or the local APIC, through the page table handed to `init_kernel_pages`.
+/// `PRESENT` is always added to `flags`.
+///
+/// If the page already maps `frame`, for example through the bootloader's identity map,
+/// only its flags are replaced. Fails with `PageAlreadyMapped` if it maps another frame,
+/// and with `FrameAllocationFailed` before `init_kernel_pages`, since page tables can't
+/// be allocated then.
+pub fn identity_map(frame: PhysFrame, flags: PageTableFlags) -> Result<(), MapToError> {
+    let page: Page = Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+    with_kernel_pages(|recursive_page_table, frame_allocator| {
+        match translate_addr(page.start_address()) {
+            Some(addr) if addr == frame.start_address() => {
+                // only fails if the page is part of a huge page
+                update_flags(recursive_page_table, page, flags)
+                    .map_err(|_| MapToError::ParentEntryHugePage)
+            }
+            Some(_) => Err(MapToError::PageAlreadyMapped),
+            // nothing else uses the frame through this page, so the mapping can't alias
+            None => unsafe { map_page(recursive_page_table, page, frame, flags, frame_allocator) },
+        }
+    })
+    .unwrap_or(Err(MapToError::FrameAllocationFailed))
+}
+
+/// Returns the number of frames the kernel's frame allocator can still hand out, or `None`
+/// before `init_kernel_pages`.
+pub fn free_frames() -> Option<usize> {
+    with_kernel_pages(|_, frame_allocator| frame_allocator.free_frames())
+}
+
+/// A `GrowFn` for the kernel heap that maps fresh pages through `KernelPages`.
+pub fn grow_heap(top: usize, size: usize) -> bool {
+    KernelPages.map_pages(top, size / PAGE_SIZE)
+}
+
+/// Maps a kernel stack of `pages` pages through `KernelPages`, with a guard page below it.
+pub fn alloc_stack(pages: usize) -> Option<Stack> {
+    without_interrupts(|| STACKS.lock().alloc(&mut KernelPages, pages))
 }
@@ -0,0 +1,122 @@
+use super::{phys_to_virt, RECURSIVE_INDEX};
+use core::ptr;
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, PageTable, PageTableFlags, PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// A set of page tables, owned through its level 4 table.
+///
+/// Level 4 entries copied by `clone_kernel_mappings` share the kernel's lower tables, so
+/// kernel mappings made later below them show up here too. Everything else is private to
+/// the address space and freed by `destroy`.
+pub struct AddressSpace {
+    p4_frame: PhysFrame,
+    /// Bit `i` is set if level 4 entry `i` is shared with the kernel.
+    shared: [u64; 8],
+}
+
+impl AddressSpace {
+    /// Creates an address space that maps nothing but its own page tables, through the
+    /// recursive entry. Returns `None` if there is no frame left, or before
+    /// `memory::map_physical_memory`, which is needed to reach the new table.
+    pub fn new(frame_allocator: &mut impl FrameAllocator<Size4KiB>) -> Option<AddressSpace> {
+        // checked before taking the frame, which would leak otherwise
+        phys_to_virt(PhysAddr::new(0))?;
+        let p4_frame = frame_allocator.allocate_frame()?;
+        let p4 = table(p4_frame).unwrap();
+        unsafe { ptr::write_bytes(p4 as *mut PageTable, 0, 1) };
+
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        p4[RECURSIVE_INDEX as usize].set_addr(p4_frame.start_address(), flags);
+        Some(AddressSpace {
+            p4_frame,
+            shared: [0; 8],
+        })
+    }
+
+    pub fn p4_frame(&self) -> PhysFrame {
+        self.p4_frame
+    }
+
+    /// Shares every level 4 entry of the active page tables with this address space, except
+    /// the recursive one. Run it while the kernel's tables are active.
+    pub fn clone_kernel_mappings(&mut self) {
+        let r = RECURSIVE_INDEX;
+        let active_p4 = VirtAddr::new(r << 39 | r << 30 | r << 21 | r << 12);
+        let active_p4 = unsafe { &*(active_p4.as_u64() as *const PageTable) };
+        let p4 = match table(self.p4_frame) {
+            Some(p4) => p4,
+            None => return,
+        };
+
+        for index in 0..512 {
+            let entry = &active_p4[index];
+            if index as u64 == r || !entry.flags().contains(PageTableFlags::PRESENT) {
+                continue;
+            }
+            p4[index].set_addr(entry.addr(), entry.flags());
+            self.shared[index / 64] |= 1 << (index % 64);
+        }
+    }
+
+    /// Makes this address space the active one.
+    ///
+    /// Unsafe because the code, stack and data in use must be mapped in it, for example
+    /// through `clone_kernel_mappings`.
+    pub unsafe fn switch(&self) {
+        Cr3::write(self.p4_frame, Cr3Flags::empty());
+    }
+
+    /// Frees the level 4 table, every table below its private entries, and the 4 KiB
+    /// frames those map. Shared kernel tables are left alone. 2 MiB and 1 GiB frames are
+    /// leaked, `frame_deallocator` only takes 4 KiB ones.
+    ///
+    /// Panics if the address space is active.
+    pub fn destroy(self, frame_deallocator: &mut impl FrameDeallocator<Size4KiB>) {
+        assert!(Cr3::read().0 != self.p4_frame, "can't destroy the active address space");
+
+        if let Some(p4) = table(self.p4_frame) {
+            for index in 0..512 {
+                let shared = self.shared[index / 64] & (1 << (index % 64)) != 0;
+                let present = p4[index].flags().contains(PageTableFlags::PRESENT);
+                if shared || index as u64 == RECURSIVE_INDEX || !present {
+                    continue;
+                }
+                free_table(PhysFrame::containing_address(p4[index].addr()), 3, frame_deallocator);
+            }
+        }
+        frame_deallocator.deallocate_frame(self.p4_frame);
+    }
+}
+
+/// Frees the table of `level` in `frame` with everything below it.
+fn free_table(
+    frame: PhysFrame,
+    level: u8,
+    frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    if let Some(table) = table(frame) {
+        for index in 0..512 {
+            let entry = &table[index];
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT) {
+                continue;
+            }
+            let child = PhysFrame::containing_address(entry.addr());
+            if level == 1 {
+                frame_deallocator.deallocate_frame(child);
+            } else if !flags.contains(PageTableFlags::HUGE_PAGE) {
+                free_table(child, level - 1, frame_deallocator);
+            }
+        }
+    }
+    frame_deallocator.deallocate_frame(frame);
+}
+
+/// Returns the page table in `frame` through the physical memory mapping.
+fn table(frame: PhysFrame) -> Option<&'static mut PageTable> {
+    let addr = phys_to_virt(frame.start_address())?;
+    Some(unsafe { &mut *addr.as_mut_ptr::<PageTable>() })
+}
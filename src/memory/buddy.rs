@@ -0,0 +1,253 @@
+use super::reserve::{frame_reserved, reservations};
+use super::{phys_to_virt, BootInfoFrameAllocator};
+use crate::large_alloc::PAGE_SIZE;
+use alloc::vec::Vec;
+use bootloader::bootinfo::{MemoryRegion, MemoryRegionType};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size2MiB, Size4KiB};
+use x86_64::PhysAddr;
+
+/// The largest blocks are `2^MAX_ORDER` frames (4 MiB).
+pub const MAX_ORDER: usize = 10;
+/// The order of a block the size of a 2 MiB page.
+pub const HUGE_PAGE_ORDER: usize = 9;
+
+/// Ends a free list. Not a frame number, since there are fewer than `2^52` frames.
+const NONE: u64 = !0;
+
+/// Kept in the first bytes of every free block.
+struct FreeBlock {
+    next: u64,
+    prev: u64,
+    order: usize,
+}
+
+/// Hands out blocks of `2^order` contiguous frames, aligned to their size.
+///
+/// Free blocks of each order are on a doubly linked list running through the blocks
+/// themselves. Freeing a block merges it with its buddy, the other half of the block of the
+/// next order, as long as that one is free too.
+pub struct BuddyFrameAllocator {
+    /// Frame number of the first free block of each order, or `NONE`.
+    free_lists: [u64; MAX_ORDER + 1],
+    /// One bit per frame, set if a free block starts there.
+    heads: Vec<u64>,
+    /// Free frames in all blocks.
+    free: usize,
+}
+
+impl BuddyFrameAllocator {
+    /// Takes over every frame `early` hasn't handed out yet, except reserved ones.
+    ///
+    /// Needs the heap, for a bitmap with one bit per frame, and `map_physical_memory`, to
+    /// reach the free blocks. Until then `BootInfoFrameAllocator` has to do.
+    pub fn new(mut early: BootInfoFrameAllocator) -> BuddyFrameAllocator {
+        assert!(
+            phys_to_virt(PhysAddr::new(0)).is_some(),
+            "the buddy allocator needs the physical memory mapping"
+        );
+        let usable = |region: &&MemoryRegion| {
+            region.region_type == MemoryRegionType::Usable
+        };
+        let frames = early
+            .memory_map
+            .iter()
+            .filter(usable)
+            .map(|region| region.range.end_frame_number)
+            .max()
+            .unwrap_or(0);
+
+        let mut buddy = BuddyFrameAllocator {
+            free_lists: [NONE; MAX_ORDER + 1],
+            heads: vec![0; ((frames + 63) / 64) as usize],
+            free: 0,
+        };
+        while early.freed > 0 {
+            match early.allocate_frame() {
+                Some(frame) => buddy.deallocate_frames(frame, 0),
+                None => break,
+            }
+        }
+        for region in early.memory_map.iter().skip(early.region).filter(usable) {
+            let start = early.next_frame.max(region.range.start_frame_number);
+            buddy.add_range(start, region.range.end_frame_number);
+        }
+        for reservation in reservations().iter().filter_map(|reservation| *reservation) {
+            let (first, last) = reservation.frames();
+            buddy.reserve_frames(first, last + 1);
+        }
+        buddy
+    }
+
+    /// Returns the number of frames that can still be allocated.
+    pub fn free_frames(&self) -> usize {
+        self.free
+    }
+
+    /// Allocates `2^order` contiguous frames aligned to their size and returns the first.
+    /// Returns `None` if no block that large is free or `order` is above `MAX_ORDER`.
+    pub fn allocate_frames(&mut self, order: usize) -> Option<PhysFrame> {
+        if order > MAX_ORDER {
+            return None;
+        }
+        let from = (order..=MAX_ORDER).find(|&from| self.free_lists[from] != NONE)?;
+        let number = self.free_lists[from];
+        self.remove(number, from);
+        // the block is too large by `from - order` orders, so its upper halves go back
+        for split in (order..from).rev() {
+            self.push(number + (1 << split), split);
+        }
+        self.free -= 1 << order;
+        Some(frame(number))
+    }
+
+    /// Frees the `2^order` frames starting at `frame`, which `allocate_frames` returned for
+    /// the same order. Frames reserved in the meantime are kept out of the free lists.
+    pub fn deallocate_frames(&mut self, frame: PhysFrame, order: usize) {
+        let number = frame.start_address().as_u64() / PAGE_SIZE as u64;
+        assert!(order <= MAX_ORDER && number % (1 << order) == 0, "misaligned block");
+        self.free += 1 << order;
+        self.release(number, order);
+        for reserved in (number..number + (1 << order)).filter(|&number| frame_reserved(number)) {
+            self.take(reserved);
+        }
+    }
+
+    /// Takes the free frames in `[first, end)` off the free lists for good, for
+    /// `memory::reserve`.
+    pub(super) fn reserve_frames(&mut self, first: u64, end: u64) {
+        for number in first..end {
+            self.take(number);
+        }
+    }
+
+    /// Takes frame `number` out of the free block containing it, giving the rest of the
+    /// block back in smaller blocks. Returns false if the frame is not free.
+    fn take(&mut self, number: u64) -> bool {
+        let block = (0..=MAX_ORDER)
+            .map(|order| (number & !((1 << order) - 1), order))
+            .find(|&(head, order)| self.is_head(head) && node(head).order == order);
+        let (mut head, mut order) = match block {
+            Some(block) => block,
+            None => return false,
+        };
+        self.remove(head, order);
+        // halve the block, keeping the half with the frame, until only the frame is left
+        while order > 0 {
+            order -= 1;
+            let upper = head + (1 << order);
+            if number >= upper {
+                self.push(head, order);
+                head = upper;
+            } else {
+                self.push(upper, order);
+            }
+        }
+        self.free -= 1;
+        true
+    }
+
+    /// Frees the frames `[start, end)` in the largest blocks their alignment allows.
+    fn add_range(&mut self, start: u64, end: u64) {
+        let mut number = start;
+        while number < end {
+            let mut order = 0;
+            while order < MAX_ORDER
+                && number % (2 << order) == 0
+                && number + (2 << order) <= end
+            {
+                order += 1;
+            }
+            self.free += 1 << order;
+            self.release(number, order);
+            number += 1 << order;
+        }
+    }
+
+    /// Puts a block on its free list, after merging it with its free buddies.
+    fn release(&mut self, mut number: u64, mut order: usize) {
+        while order < MAX_ORDER {
+            let buddy = number ^ (1 << order);
+            if !self.is_head(buddy) || node(buddy).order != order {
+                break;
+            }
+            self.remove(buddy, order);
+            number = number.min(buddy);
+            order += 1;
+        }
+        self.push(number, order);
+    }
+
+    fn is_head(&self, number: u64) -> bool {
+        match self.heads.get((number / 64) as usize) {
+            Some(word) => word & (1 << (number % 64)) != 0,
+            None => false,
+        }
+    }
+
+    fn push(&mut self, number: u64, order: usize) {
+        let next = self.free_lists[order];
+        *node(number) = FreeBlock {
+            next,
+            prev: NONE,
+            order,
+        };
+        if next != NONE {
+            node(next).prev = number;
+        }
+        self.free_lists[order] = number;
+        self.heads[(number / 64) as usize] |= 1 << (number % 64);
+    }
+
+    fn remove(&mut self, number: u64, order: usize) {
+        let (next, prev) = {
+            let block = node(number);
+            (block.next, block.prev)
+        };
+        if prev == NONE {
+            self.free_lists[order] = next;
+        } else {
+            node(prev).next = next;
+        }
+        if next != NONE {
+            node(next).prev = prev;
+        }
+        self.heads[(number / 64) as usize] &= !(1 << (number % 64));
+    }
+}
+
+fn frame(number: u64) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(number * PAGE_SIZE as u64))
+}
+
+/// The header of the free block starting at frame `number`.
+fn node(number: u64) -> &'static mut FreeBlock {
+    // `new` checked that the mapping exists
+    let addr = phys_to_virt(frame(number).start_address()).unwrap();
+    unsafe { &mut *addr.as_mut_ptr::<FreeBlock>() }
+}
+
+impl FrameAllocator<Size4KiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.allocate_frames(0)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyFrameAllocator {
+    fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.deallocate_frames(frame, 0)
+    }
+}
+
+impl FrameAllocator<Size2MiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let frame = self.allocate_frames(HUGE_PAGE_ORDER)?;
+        Some(PhysFrame::containing_address(frame.start_address()))
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BuddyFrameAllocator {
+    fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        let frame = PhysFrame::containing_address(frame.start_address());
+        self.deallocate_frames(frame, HUGE_PAGE_ORDER)
+    }
+}
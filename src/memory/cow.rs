@@ -0,0 +1,106 @@
+use super::{map_page, page_flags, phys_to_virt, unmap_page, update_flags, KERNEL_PAGES};
+use crate::large_alloc::PAGE_SIZE;
+use core::ptr;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::{
+    FlagUpdateError, FrameAllocator, FrameDeallocator, Page, PageTableFlags, PhysFrame,
+    RecursivePageTable,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Software bit of a level 1 entry marking a read-only page that is copied on write.
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Makes the mapped `page` read-only, so that the first write to it copies the frame and
+/// maps the copy writable. Mapping the same frame copy-on-write elsewhere as well shares
+/// it until one side writes.
+///
+/// Frames have no reference counts yet, so the frame is copied on every write fault, even
+/// when nothing else maps it any more, and the original is never freed.
+pub fn mark_copy_on_write(
+    recursive_page_table: &mut RecursivePageTable,
+    page: Page,
+) -> Result<(), FlagUpdateError> {
+    let flags = page_flags(page).ok_or(FlagUpdateError::PageNotMapped)?;
+    update_flags(
+        recursive_page_table,
+        page,
+        (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE,
+    )
+}
+
+/// Called by the page fault handler for a write to a present page. If the page is marked
+/// copy-on-write, gives it a private, writable copy of its frame and returns true.
+///
+/// Needs the page table from `memory::init_kernel_pages` and `memory::map_physical_memory`
+/// to reach the new frame; returns false without them, or if the page table is locked by
+/// the interrupted code.
+pub fn handle_copy_on_write_fault(addr: VirtAddr) -> bool {
+    let page: Page = Page::containing_address(addr);
+    let flags = match page_flags(page) {
+        Some(flags) if flags.contains(COPY_ON_WRITE) => flags,
+        _ => return false,
+    };
+
+    without_interrupts(|| {
+        let mut kernel_pages = match KERNEL_PAGES.try_lock() {
+            Some(kernel_pages) => kernel_pages,
+            None => return false,
+        };
+        let pages = match kernel_pages.as_mut() {
+            Some(pages) => pages,
+            None => return false,
+        };
+
+        // checked before taking a frame, which would leak otherwise
+        if phys_to_virt(PhysAddr::new(0)).is_none() {
+            return false;
+        }
+        let frame: PhysFrame = match pages.frame_allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => return false,
+        };
+        let copy = phys_to_virt(frame.start_address()).unwrap();
+        unsafe {
+            ptr::copy_nonoverlapping(
+                page.start_address().as_ptr::<u8>(),
+                copy.as_mut_ptr::<u8>(),
+                PAGE_SIZE,
+            );
+        }
+
+        // the shared frame stays mapped wherever else it is
+        let shared = match unmap_page(&mut pages.recursive_page_table, page) {
+            Ok(shared) => shared,
+            Err(_) => {
+                pages.frame_allocator.deallocate_frame(frame);
+                return false;
+            }
+        };
+        let writable = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+        let mapped = unsafe {
+            map_page(
+                &mut pages.recursive_page_table,
+                page,
+                frame,
+                writable,
+                &mut pages.frame_allocator,
+            )
+        };
+        if mapped.is_ok() {
+            return true;
+        }
+        // map the shared frame again; unmapping kept its page table, so this needs no frame
+        let _ = unsafe {
+            map_page(
+                &mut pages.recursive_page_table,
+                page,
+                shared,
+                flags,
+                &mut pages.frame_allocator,
+            )
+        };
+        pages.frame_allocator.deallocate_frame(frame);
+        false
+    })
+}
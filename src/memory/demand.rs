@@ -0,0 +1,58 @@
+use super::virt_region::VirtualRegion;
+use super::KERNEL_PAGES;
+use crate::large_alloc::{PageProvider, PAGE_SIZE};
+use core::ptr;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::VirtAddr;
+
+/// Virtual region demand paged regions are carved from (64 GiB).
+const DEMAND_REGION_START: usize = 0x_6666_0000_0000;
+const DEMAND_REGION_END: usize = DEMAND_REGION_START + 64 * 1024 * 1024 * 1024;
+
+static REGION: VirtualRegion = VirtualRegion::new(DEMAND_REGION_START, DEMAND_REGION_END);
+
+/// Reserves `pages` pages of zeroed memory without mapping any of them. Each page gets a
+/// frame when it is first touched, from the page fault handler.
+///
+/// Returns `None` if there is no free region slot or the virtual range is used up.
+pub fn alloc_demand_paged(pages: usize) -> Option<VirtAddr> {
+    let range = REGION.reserve(pages)?;
+    Some(VirtAddr::new(range.start as u64))
+}
+
+/// Unmaps the pages of the region starting at `start` that were touched, gives their frames
+/// back, and forgets the region. Does nothing if no region starts there.
+pub fn free_demand_paged(start: VirtAddr) {
+    if let Some(range) = REGION.release(start.as_u64() as usize) {
+        super::KernelPages.unmap_pages(range.start, range.pages());
+    }
+}
+
+/// Called by the page fault handler for an access to a page that isn't present. If the page
+/// belongs to a demand paged region, maps a zeroed frame there and returns true.
+///
+/// Returns false if the page table or region list is locked by the interrupted code, or
+/// if there are no frames left; the fault is then reported as usual.
+pub fn handle_demand_fault(addr: VirtAddr) -> bool {
+    let addr = addr.as_u64() as usize;
+    if !REGION.covers(addr) || !REGION.try_contains(addr) {
+        return false;
+    }
+
+    without_interrupts(|| {
+
+        let page = addr & !(PAGE_SIZE - 1);
+        let mapped = match KERNEL_PAGES.try_lock() {
+            Some(mut kernel_pages) => match kernel_pages.as_mut() {
+                Some(pages) => pages.map_pages(page, 1),
+                None => false,
+            },
+            None => false,
+        };
+        if mapped {
+            // frames come straight from the allocator and may hold anything
+            unsafe { ptr::write_bytes(page as *mut u8, 0, PAGE_SIZE) };
+        }
+        mapped
+    })
+}
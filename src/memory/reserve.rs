@@ -0,0 +1,114 @@
+use super::with_kernel_pages;
+use crate::large_alloc::PAGE_SIZE;
+use crate::serial_println;
+use core::ops::Range;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::PhysAddr;
+
+const MAX_RESERVATIONS: usize = 32;
+
+/// Physical memory reserved by one driver for the lifetime of the kernel, like MMIO
+/// registers, a framebuffer or firmware tables. The frame allocators never hand out frames
+/// that overlap a reservation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reservation {
+    start: u64,
+    /// Exclusive.
+    end: u64,
+    owner: &'static str,
+}
+
+static RESERVATIONS: Mutex<[Option<Reservation>; MAX_RESERVATIONS]> =
+    Mutex::new([None; MAX_RESERVATIONS]);
+
+/// Reserves the physical range `range` for `owner`.
+///
+/// Overlapping an earlier reservation is a bug: it panics in debug builds and is reported
+/// on the serial port otherwise, keeping both reservations. Frames of the range the frame
+/// allocator has handed out already stay with whoever has them.
+pub fn reserve(range: Range<PhysAddr>, owner: &'static str) -> Reservation {
+    let reservation = Reservation {
+        start: range.start.as_u64(),
+        end: range.end.as_u64(),
+        owner,
+    };
+    assert!(reservation.start < reservation.end, "empty physical range reserved by {}", owner);
+
+    let conflict = without_interrupts(|| {
+        let mut reservations = RESERVATIONS.lock();
+        let conflict = reservations
+            .iter()
+            .filter_map(|reservation| *reservation)
+            .find(|other| other.overlaps(reservation.start, reservation.end));
+        match reservations.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => *slot = Some(reservation),
+            None => panic!("too many memory reservations, {} does not fit", owner),
+        }
+        conflict
+    });
+
+    if let Some(other) = conflict {
+        if cfg!(debug_assertions) {
+            panic!("{} reserves {} which overlaps {}", owner, reservation, other);
+        }
+        serial_println!("memory: {} reserves {} which overlaps {}", owner, reservation, other);
+    }
+
+    let (first, last) = reservation.frames();
+    with_kernel_pages(|_, frame_allocator| frame_allocator.reserve_frames(first, last + 1));
+    reservation
+}
+
+impl Reservation {
+    pub fn owner(&self) -> &'static str {
+        self.owner
+    }
+
+    pub fn contains(&self, addr: PhysAddr) -> bool {
+        self.start <= addr.as_u64() && addr.as_u64() < self.end
+    }
+
+    /// Returns the numbers of the first and last frame the reservation touches.
+    pub(super) fn frames(&self) -> (u64, u64) {
+        (self.start / PAGE_SIZE as u64, (self.end - 1) / PAGE_SIZE as u64)
+    }
+
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start < end && start < self.end
+    }
+}
+
+impl core::fmt::Display for Reservation {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "physical {:#x}..{:#x} of {}", self.start, self.end, self.owner)
+    }
+}
+
+/// Returns whether any part of frame `number` is reserved.
+pub(super) fn frame_reserved(number: u64) -> bool {
+    let start = number * PAGE_SIZE as u64;
+    without_interrupts(|| {
+        RESERVATIONS
+            .lock()
+            .iter()
+            .filter_map(|reservation| *reservation)
+            .any(|reservation| reservation.overlaps(start, start + PAGE_SIZE as u64))
+    })
+}
+
+/// Returns the reservation containing `addr`, if any.
+pub fn reserved_by(addr: PhysAddr) -> Option<Reservation> {
+    without_interrupts(|| {
+        RESERVATIONS
+            .lock()
+            .iter()
+            .filter_map(|reservation| *reservation)
+            .find(|reservation| reservation.contains(addr))
+    })
+}
+
+/// Returns all reservations, in the order they were made.
+pub fn reservations() -> [Option<Reservation>; MAX_RESERVATIONS] {
+    without_interrupts(|| *RESERVATIONS.lock())
+}
@@ -0,0 +1,63 @@
+use crate::large_alloc::{PageProvider, PAGE_SIZE};
+use x86_64::VirtAddr;
+
+/// A mapped kernel stack. It grows down from `top` to `bottom`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stack {
+    top: VirtAddr,
+    bottom: VirtAddr,
+}
+
+impl Stack {
+    /// Returns the address just past the highest byte, the initial stack pointer.
+    pub fn top(&self) -> VirtAddr {
+        self.top
+    }
+
+    /// Returns the address of the lowest byte.
+    pub fn bottom(&self) -> VirtAddr {
+        self.bottom
+    }
+
+    pub fn size(&self) -> usize {
+        (self.top.as_u64() - self.bottom.as_u64()) as usize
+    }
+}
+
+/// Carves stacks out of a reserved virtual range, each with an unmapped guard page below it
+/// so an overflow page faults instead of corrupting the stack below. Stacks are never freed.
+pub struct StackAllocator {
+    next: usize,
+    end: usize,
+}
+
+impl StackAllocator {
+    /// Creates an allocator for the page aligned range `[start, end)`, which must not be
+    /// used for anything else.
+    pub const fn new(start: usize, end: usize) -> StackAllocator {
+        StackAllocator { next: start, end }
+    }
+
+    /// Maps a stack of `pages` pages through `provider`. Returns `None` if the range is used
+    /// up or mapping fails; in the latter case the range taken for the stack is lost.
+    pub fn alloc(&mut self, provider: &mut dyn PageProvider, pages: usize) -> Option<Stack> {
+        if pages == 0 {
+            return None;
+        }
+        let guard = self.next;
+        let bottom = guard + PAGE_SIZE;
+        let top = bottom + pages * PAGE_SIZE;
+        if top > self.end {
+            return None;
+        }
+        self.next = top;
+
+        if !provider.map_pages(bottom, pages) {
+            return None;
+        }
+        Some(Stack {
+            top: VirtAddr::new(top as u64),
+            bottom: VirtAddr::new(bottom as u64),
+        })
+    }
+}
@@ -0,0 +1,51 @@
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::tlb;
+use x86_64::VirtAddr;
+
+/// Invalidates TLB entries after a mapping changed.
+///
+/// The page table functions in `memory` flush through the flusher set with
+/// `set_tlb_flusher`, so that SMP support can swap in one that shoots down the entries of
+/// the other CPUs as well.
+pub trait TlbFlusher: Sync {
+    /// Invalidates the entries for the page containing `addr`, whatever its size.
+    fn flush_page(&self, addr: VirtAddr);
+
+    /// Invalidates all entries except global ones.
+    fn flush_all(&self);
+}
+
+/// Flushes the TLB of the current CPU only. The default flusher.
+pub struct LocalTlb;
+
+impl TlbFlusher for LocalTlb {
+    fn flush_page(&self, addr: VirtAddr) {
+        tlb::flush(addr);
+    }
+
+    fn flush_all(&self) {
+        tlb::flush_all();
+    }
+}
+
+static FLUSHER: Mutex<&'static dyn TlbFlusher> = Mutex::new(&LocalTlb);
+
+/// Replaces the flusher used by `flush_tlb_page` and `flush_tlb_all`.
+pub fn set_tlb_flusher(flusher: &'static dyn TlbFlusher) {
+    without_interrupts(|| *FLUSHER.lock() = flusher);
+}
+
+/// Invalidates the TLB entries for the page containing `addr`.
+pub fn flush_tlb_page(addr: VirtAddr) {
+    flusher().flush_page(addr);
+}
+
+/// Invalidates all non-global TLB entries.
+pub fn flush_tlb_all() {
+    flusher().flush_all();
+}
+
+fn flusher() -> &'static dyn TlbFlusher {
+    without_interrupts(|| *FLUSHER.lock())
+}
@@ -0,0 +1,107 @@
+use crate::large_alloc::PAGE_SIZE;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Ranges one region can have reserved at a time.
+const MAX_RANGES: usize = 64;
+
+/// Pages reserved with `VirtualRegion::reserve`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtualRange {
+    pub start: usize,
+    /// Exclusive.
+    pub end: usize,
+}
+
+impl VirtualRange {
+    pub fn pages(&self) -> usize {
+        (self.end - self.start) / PAGE_SIZE
+    }
+
+    pub fn contains(&self, addr: usize) -> bool {
+        self.start <= addr && addr < self.end
+    }
+}
+
+struct Ranges {
+    ranges: [Option<VirtualRange>; MAX_RANGES],
+    /// Where the next range starts.
+    next: usize,
+}
+
+/// A fixed stretch of virtual address space that ranges of pages are reserved from, for the
+/// allocators that map pages themselves. Nothing is mapped or unmapped here.
+///
+/// Addresses are never reused, so a stale pointer into a released range faults, and ranges
+/// are one unmapped page apart, so running off the end of one faults as well.
+pub struct VirtualRegion {
+    start: usize,
+    end: usize,
+    ranges: Mutex<Ranges>,
+}
+
+impl VirtualRegion {
+    /// Creates the region `[start, end)`, both page aligned.
+    pub const fn new(start: usize, end: usize) -> VirtualRegion {
+        VirtualRegion {
+            start,
+            end,
+            ranges: Mutex::new(Ranges {
+                ranges: [None; MAX_RANGES],
+                next: start,
+            }),
+        }
+    }
+
+    /// Reserves `pages` pages. Returns `None` for 0 pages, if all range slots are taken, or
+    /// if the region is used up.
+    pub fn reserve(&self, pages: usize) -> Option<VirtualRange> {
+        if pages == 0 {
+            return None;
+        }
+        without_interrupts(|| {
+            let mut ranges = self.ranges.lock();
+            let start = ranges.next;
+            let end = start.checked_add(pages.checked_mul(PAGE_SIZE)?)?;
+            if end > self.end {
+                return None;
+            }
+            let slot = ranges.ranges.iter_mut().find(|slot| slot.is_none())?;
+            let range = VirtualRange { start, end };
+            *slot = Some(range);
+            ranges.next = end + PAGE_SIZE;
+            Some(range)
+        })
+    }
+
+    /// Forgets the range starting at `start` and returns it, or `None` if no range starts
+    /// there.
+    pub fn release(&self, start: usize) -> Option<VirtualRange> {
+        without_interrupts(|| {
+            let mut ranges = self.ranges.lock();
+            let slot = ranges.ranges.iter_mut().find(|slot| match slot {
+                Some(range) => range.start == start,
+                None => false,
+            })?;
+            slot.take()
+        })
+    }
+
+    /// Returns whether `addr` lies in the region, reserved or not.
+    pub fn covers(&self, addr: usize) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    /// Returns whether `addr` lies in a reserved range. For the page fault handler: returns
+    /// false instead of waiting if the interrupted code holds the ranges.
+    pub fn try_contains(&self, addr: usize) -> bool {
+        without_interrupts(|| match self.ranges.try_lock() {
+            Some(ranges) => ranges
+                .ranges
+                .iter()
+                .filter_map(|slot| *slot)
+                .any(|range| range.contains(addr)),
+            None => false,
+        })
+    }
+}
@@ -0,0 +1,36 @@
+use super::virt_region::VirtualRegion;
+use crate::large_alloc::PageProvider;
+use x86_64::VirtAddr;
+
+/// Virtual region `vmalloc` ranges are carved from (64 GiB).
+const VMALLOC_REGION_START: usize = 0x_7777_0000_0000;
+const VMALLOC_REGION_END: usize = VMALLOC_REGION_START + 64 * 1024 * 1024 * 1024;
+
+static REGION: VirtualRegion = VirtualRegion::new(VMALLOC_REGION_START, VMALLOC_REGION_END);
+
+/// Maps `pages` pages that are contiguous in virtual memory but take their frames from
+/// wherever the frame allocator finds them. The pages are not zeroed. Free them with `vfree`.
+///
+/// Heap allocations above a page already get pages of their own from the heap's large
+/// allocation path, so `Box` and `Vec` are the way to a multi-page buffer. `vmalloc` is for
+/// memory outside the heap: buffers larger than the 16 MiB the large allocation path covers,
+/// or ones that must not count towards the heap's budget.
+///
+/// Returns `None` if there is no free range slot, the virtual region is used up, or there
+/// are not enough frames.
+pub fn vmalloc(pages: usize) -> Option<VirtAddr> {
+    let range = REGION.reserve(pages)?;
+    if !super::KernelPages.map_pages(range.start, pages) {
+        REGION.release(range.start);
+        return None;
+    }
+    Some(VirtAddr::new(range.start as u64))
+}
+
+/// Unmaps the range `vmalloc` returned at `start` and gives its frames back. Does nothing
+/// if no range starts there.
+pub fn vfree(start: VirtAddr) {
+    if let Some(range) = REGION.release(start.as_u64() as usize) {
+        super::KernelPages.unmap_pages(range.start, range.pages());
+    }
+}
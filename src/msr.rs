@@ -0,0 +1,144 @@
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::idt::ExceptionStackFrame;
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Typed EFER access, as x86_64 has it already.
+pub use x86_64::registers::model_specific::{Efer, EferFlags};
+
+/// The model specific registers the kernel uses. Registers it writes have a type here,
+/// EFER's is the one of x86_64.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Register {
+    ApicBase = 0x1b,
+    Mperf = 0xe7,
+    Aperf = 0xe8,
+    ThermStatus = 0x19c,
+    /// Intel only.
+    TemperatureTarget = 0x1a2,
+    Pat = 0x277,
+}
+
+const RDMSR: [u8; 2] = [0x0f, 0x32];
+const WRMSR: [u8; 2] = [0x0f, 0x30];
+
+static PROBING: AtomicBool = AtomicBool::new(false);
+static PROBE_FAULTED: AtomicBool = AtomicBool::new(false);
+
+/// Reads `register`. Faults if the CPU does not implement it, use `probe` for optional ones.
+pub unsafe fn read(register: Register) -> u64 {
+    Msr::new(register as u32).read()
+}
+
+unsafe fn write(register: Register, value: u64) {
+    Msr::new(register as u32).write(value)
+}
+
+/// `IA32_APIC_BASE`, where the local APIC's registers are and whether it is enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ApicBase {
+    /// Physical address of the 4 KiB page of registers.
+    pub address: PhysAddr,
+    /// Set on the CPU that booted the system.
+    pub bootstrap: bool,
+    pub x2apic: bool,
+    pub enabled: bool,
+}
+
+impl ApicBase {
+    const BOOTSTRAP: u64 = 1 << 8;
+    const X2APIC: u64 = 1 << 10;
+    const ENABLED: u64 = 1 << 11;
+    const ADDRESS: u64 = 0x000f_ffff_ffff_f000;
+
+    pub fn read() -> ApicBase {
+        // every x86_64 CPU has a local APIC
+        let raw = unsafe { read(Register::ApicBase) };
+        ApicBase {
+            address: PhysAddr::new(raw & Self::ADDRESS),
+            bootstrap: raw & Self::BOOTSTRAP != 0,
+            x2apic: raw & Self::X2APIC != 0,
+            enabled: raw & Self::ENABLED != 0,
+        }
+    }
+
+    /// Writes the register back, keeping its reserved bits. Unsafe since moving or
+    /// disabling the APIC breaks everything using it.
+    pub unsafe fn write(self) {
+        let flag = |set: bool, bit: u64| if set { bit } else { 0 };
+        let reserved = read(Register::ApicBase)
+            & !(Self::ADDRESS | Self::BOOTSTRAP | Self::X2APIC | Self::ENABLED);
+        let raw = reserved
+            | self.address.as_u64() & Self::ADDRESS
+            | flag(self.bootstrap, Self::BOOTSTRAP)
+            | flag(self.x2apic, Self::X2APIC)
+            | flag(self.enabled, Self::ENABLED);
+        write(Register::ApicBase, raw)
+    }
+}
+
+/// A memory type, as the entries of the PAT encode it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MemoryType {
+    Uncacheable = 0x00,
+    WriteCombining = 0x01,
+    WriteThrough = 0x04,
+    WriteProtected = 0x05,
+    WriteBack = 0x06,
+    /// Uncacheable, unless an MTRR makes the range write-combining.
+    UncachedMinus = 0x07,
+}
+
+/// `IA32_PAT`, the memory type of each combination of the PAT, PCD and PWT bits of a page
+/// table entry, entry `PAT << 2 | PCD << 1 | PWT`.
+pub struct Pat;
+
+impl Pat {
+    /// Unsafe since it changes the memory type of every mapping that uses a changed entry.
+    /// Caches and TLBs must be flushed around it.
+    pub unsafe fn write(entries: [MemoryType; 8]) {
+        let raw = entries
+            .iter()
+            .enumerate()
+            .fold(0, |raw, (index, &entry)| raw | (entry as u64) << (8 * index));
+        write(Register::Pat, raw)
+    }
+}
+
+/// Reads `register`, returning `None` instead of faulting if the CPU does not implement it.
+pub fn probe(register: Register) -> Option<u64> {
+    without_interrupts(|| {
+        PROBE_FAULTED.store(false, Ordering::SeqCst);
+        PROBING.store(true, Ordering::SeqCst);
+        let value = unsafe { read(register) };
+        PROBING.store(false, Ordering::SeqCst);
+
+        if PROBE_FAULTED.load(Ordering::SeqCst) {
+            None
+        } else {
+            Some(value)
+        }
+    })
+}
+
+/// Called by the #GP handler. If the fault came from an MSR access in `probe`, skips the
+/// instruction and returns true.
+pub(crate) fn recover_probe(stack_frame: &mut ExceptionStackFrame) -> bool {
+    if !PROBING.load(Ordering::SeqCst) {
+        return false;
+    }
+
+    let rip = stack_frame.instruction_pointer.as_u64();
+    let opcode = unsafe { ptr::read(rip as *const [u8; 2]) };
+    if opcode != RDMSR && opcode != WRMSR {
+        return false;
+    }
+    PROBE_FAULTED.store(true, Ordering::SeqCst);
+    // volatile, so the write to the frame the CPU returns through is not optimized away
+    unsafe { ptr::write_volatile(&mut stack_frame.instruction_pointer, VirtAddr::new(rip + 2)) };
+    true
+}
@@ -0,0 +1,81 @@
+use crate::vga_buffer::{Color, WRITER};
+use crate::crashdump::{self, Registers};
+use crate::{backtrace, config, println, serial_println, smp, symbols, QemuExitCode};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+static TEST_MODE: AtomicBool = AtomicBool::new(false);
+
+/// Set by the first CPU that panics.
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// Switches panics to test harness output. Integration tests call this first in `_start`.
+pub fn enter_test_mode() {
+    TEST_MODE.store(true, Ordering::SeqCst);
+}
+
+pub fn is_test_mode() -> bool {
+    TEST_MODE.load(Ordering::SeqCst)
+}
+
+/// Shared body of the `#[panic_handler]` of every binary.
+///
+/// In test mode this prints `[failed]`, the panic message, and a backtrace to the serial
+/// port, then exits QEMU with a failure code. Otherwise it paints the panic screen on the
+/// VGA buffer and halts.
+///
+/// Every other CPU is stopped first, so nothing mutates shared state while the
+/// diagnostics are written. A CPU that panics while another one is already panicking
+/// just halts.
+///
+/// With the `panic.crash_dump` knob set, a crash dump goes to the serial port before
+/// anything else.
+pub fn handle(info: &PanicInfo) -> ! {
+    let registers = Registers::capture();
+    x86_64::instructions::interrupts::disable();
+
+    if PANICKING.swap(true, Ordering::SeqCst) {
+        crate::idle::halt();
+    }
+    smp::stop_other_cpus();
+
+    if config::PANIC_CRASH_DUMP.get() != 0 {
+        crashdump::write(&registers);
+    }
+
+    if is_test_mode() {
+        test_panic(info)
+    } else {
+        kernel_panic(info)
+    }
+}
+
+fn test_panic(info: &PanicInfo) -> ! {
+    // the panic may have happened while the port was locked by this very CPU
+    unsafe { crate::serial::SERIAL1.force_unlock() };
+
+    serial_println!("[failed]");
+    serial_println!("{}", info);
+    backtrace::walk(|addr| serial_println!("  at {}", symbols::Address(addr)));
+
+    unsafe {
+        crate::exit_qemu_with(QemuExitCode::Failed);
+    }
+    crate::idle::halt();
+}
+
+fn kernel_panic(info: &PanicInfo) -> ! {
+    unsafe { WRITER.force_unlock() };
+    {
+        let mut writer = WRITER.lock();
+        writer.set_color(Color::White, Color::Red);
+        writer.clear_screen();
+    }
+
+    println!("KERNEL PANIC");
+    println!("{}", info);
+    backtrace::walk(|addr| println!("  at {}", symbols::Address(addr)));
+
+    // there is no debug monitor yet, so halting is all that is left
+    crate::idle::halt();
+}
@@ -0,0 +1,47 @@
+use crate::memory;
+use crate::msr::{MemoryType, Pat};
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::interrupts;
+
+/// The power-on PAT with entry 5 switched from write-through to write-combining.
+/// Entries 0 to 3 keep their defaults, so mappings that don't set the PAT bit are unaffected.
+const LAYOUT: [MemoryType; 8] = [
+    MemoryType::WriteBack,
+    MemoryType::WriteThrough,
+    MemoryType::UncachedMinus,
+    MemoryType::Uncacheable,
+    MemoryType::WriteBack,
+    MemoryType::WriteCombining,
+    MemoryType::UncachedMinus,
+    MemoryType::Uncacheable,
+];
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Returns whether the CPU has a page attribute table.
+pub fn is_supported() -> bool {
+    unsafe { __cpuid(1).edx & (1 << 16) != 0 }
+}
+
+/// Programs the PAT so that write-combining mappings are possible.
+/// Does nothing on CPUs without a PAT.
+pub fn init() {
+    if !is_supported() {
+        return;
+    }
+
+    interrupts::without_interrupts(|| unsafe {
+        // no cache line or TLB entry may keep a stale memory type
+        asm!("wbinvd" :::: "volatile");
+        Pat::write(LAYOUT);
+        asm!("wbinvd" :::: "volatile");
+        memory::flush_tlb_all();
+    });
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Returns whether `init` set up the write-combining entry.
+pub fn write_combining_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
@@ -0,0 +1,95 @@
+use crate::portio::PortRange;
+use core::arch::x86_64::_rdtsc;
+use lazy_static::lazy_static;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Input clock of the PIT in Hz.
+pub const BASE_FREQUENCY: u32 = 1_193_182;
+
+const CHANNEL_0_PORT: u16 = 0x40;
+const CHANNEL_2_PORT: u16 = 0x42;
+const COMMAND_PORT: u16 = 0x43;
+/// Gate and output of channel 2, shared with the PC speaker.
+const SPEAKER_PORT: u16 = 0x61;
+
+const SPEAKER_GATE: u8 = 1;
+const SPEAKER_DATA: u8 = 1 << 1;
+const CHANNEL_2_OUT: u8 = 1 << 5;
+
+/// Input clock cycles `tsc_frequency` counts down, 10 ms.
+const CALIBRATION_COUNT: u16 = (BASE_FREQUENCY / 100) as u16;
+/// Polls of the speaker port before `tsc_frequency` gives up, far more than 10 ms of them.
+const CALIBRATION_POLLS: usize = 1_000_000;
+
+lazy_static! {
+    static ref PORTS: PortRange = PortRange::claim(CHANNEL_0_PORT..=COMMAND_PORT, "pit");
+    static ref SPEAKER_PORTS: PortRange = PortRange::claim(SPEAKER_PORT..=SPEAKER_PORT, "pit");
+}
+
+crate::kernel_driver!(PIT_DRIVER, "pit", claim_ports);
+
+fn claim_ports() -> Result<(), &'static str> {
+    lazy_static::initialize(&PORTS);
+    lazy_static::initialize(&SPEAKER_PORTS);
+    Ok(())
+}
+
+/// Programs channel 0 to fire IRQ 0 roughly `hz` times per second.
+///
+/// Rates below ~19 Hz can't be represented and fall back to the slowest one.
+pub fn set_frequency(hz: u32) {
+    let divisor = BASE_FREQUENCY / hz;
+    if divisor > 0xffff {
+        set_divisor(0)
+    } else {
+        set_divisor(divisor as u16)
+    }
+}
+
+/// Restores the BIOS default rate of ~18.2 Hz.
+pub fn reset() {
+    set_divisor(0)
+}
+
+/// Measures the TSC rate in cycles per second against a 10 ms countdown of channel 2, which
+/// runs from the same input clock as channel 0 but raises no interrupt. Returns `None` if
+/// the countdown never finishes.
+pub fn tsc_frequency() -> Option<u64> {
+    let mut speaker = SPEAKER_PORTS.port::<u8>(SPEAKER_PORT);
+    let mut command = PORTS.port::<u8>(COMMAND_PORT);
+    let mut channel_2 = PORTS.port::<u8>(CHANNEL_2_PORT);
+
+    let cycles = without_interrupts(|| unsafe {
+        let control = speaker.read();
+        // gate on, speaker off
+        speaker.write(control & !SPEAKER_DATA | SPEAKER_GATE);
+        // channel 2, low byte then high byte, mode 0 (interrupt on terminal count)
+        command.write(0xb0);
+        channel_2.write(CALIBRATION_COUNT as u8);
+        channel_2.write((CALIBRATION_COUNT >> 8) as u8);
+
+        let start = _rdtsc();
+        let finished = (0..CALIBRATION_POLLS).any(|_| speaker.read() & CHANNEL_2_OUT != 0);
+        let end = _rdtsc();
+        speaker.write(control);
+        if finished {
+            Some(end - start)
+        } else {
+            None
+        }
+    })?;
+    Some(cycles * u64::from(BASE_FREQUENCY) / u64::from(CALIBRATION_COUNT))
+}
+
+/// Programs channel 0 as a rate generator with the given divisor, where 0 means 65536.
+fn set_divisor(divisor: u16) {
+    let mut command = PORTS.port::<u8>(COMMAND_PORT);
+    let mut channel_0 = PORTS.port::<u8>(CHANNEL_0_PORT);
+
+    unsafe {
+        // channel 0, low byte then high byte, mode 2 (rate generator)
+        command.write(0x34);
+        channel_0.write(divisor as u8);
+        channel_0.write((divisor >> 8) as u8);
+    }
+}
@@ -0,0 +1,103 @@
+use crate::serial_println;
+use core::ops::RangeInclusive;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::{Port, PortReadWrite};
+
+const MAX_CLAIMS: usize = 32;
+
+/// I/O ports claimed by one driver for the lifetime of the kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    first: u16,
+    last: u16,
+    owner: &'static str,
+}
+
+static CLAIMS: Mutex<[Option<PortRange>; MAX_CLAIMS]> = Mutex::new([None; MAX_CLAIMS]);
+
+impl PortRange {
+    /// Claims `ports` for `owner`.
+    ///
+    /// Overlapping an earlier claim is a bug: it panics in debug builds and is reported on
+    /// the serial port otherwise, keeping both claims.
+    pub fn claim(ports: RangeInclusive<u16>, owner: &'static str) -> PortRange {
+        let (range, conflict) = PortRange::claim_unreported(ports, owner);
+        if let Some(other) = conflict {
+            if cfg!(debug_assertions) {
+                panic!("{} claims {} which overlaps {}", owner, range, other);
+            }
+            serial_println!("portio: {} claims {} which overlaps {}", owner, range, other);
+        }
+        range
+    }
+
+    /// Claims `ports` for `owner` like `claim`, but returns an overlapping earlier claim
+    /// instead of reporting it. For the serial driver, which `claim` reports through.
+    pub fn claim_unreported(
+        ports: RangeInclusive<u16>,
+        owner: &'static str,
+    ) -> (PortRange, Option<PortRange>) {
+        let range = PortRange {
+            first: *ports.start(),
+            last: *ports.end(),
+            owner,
+        };
+        assert!(range.first <= range.last, "empty port range claimed by {}", owner);
+
+        let conflict = without_interrupts(|| {
+            let mut claims = CLAIMS.lock();
+            let conflict = claims
+                .iter()
+                .filter_map(|claim| *claim)
+                .find(|claim| claim.overlaps(&range));
+            match claims.iter_mut().find(|claim| claim.is_none()) {
+                Some(slot) => *slot = Some(range),
+                None => panic!("too many port claims, {} does not fit", owner),
+            }
+            conflict
+        });
+        (range, conflict)
+    }
+
+    pub fn owner(&self) -> &'static str {
+        self.owner
+    }
+
+    pub fn contains(&self, port: u16) -> bool {
+        self.first <= port && port <= self.last
+    }
+
+    /// Returns a handle for one of the claimed ports. Panics if `port` is outside the claim.
+    pub fn port<T: PortReadWrite>(&self, port: u16) -> Port<T> {
+        assert!(self.contains(port), "{} does not own port {:#x}", self.owner, port);
+        Port::new(port)
+    }
+
+    fn overlaps(&self, other: &PortRange) -> bool {
+        self.first <= other.last && other.first <= self.last
+    }
+}
+
+impl core::fmt::Display for PortRange {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "ports {:#x}..={:#x} of {}", self.first, self.last, self.owner)
+    }
+}
+
+/// Returns the driver that claimed `port`, if any.
+pub fn owner_of(port: u16) -> Option<&'static str> {
+    without_interrupts(|| {
+        CLAIMS
+            .lock()
+            .iter()
+            .filter_map(|claim| *claim)
+            .find(|claim| claim.contains(port))
+            .map(|claim| claim.owner)
+    })
+}
+
+/// Returns all claims, in the order they were made.
+pub fn claims() -> [Option<PortRange>; MAX_CLAIMS] {
+    without_interrupts(|| *CLAIMS.lock())
+}
@@ -0,0 +1,47 @@
+use crate::portio::PortRange;
+use crate::serial_println;
+use lazy_static::lazy_static;
+
+/// The POST diagnostic port. Writes are visible in QEMU with
+/// `-device isa-debugcon,iobase=0x80` or on a POST card on real hardware, even when nothing
+/// else works.
+const POST_PORT: u16 = 0x80;
+
+lazy_static! {
+    // claimed on first use, since the first stages are reported before `driver::init_all`
+    static ref PORTS: PortRange = PortRange::claim(POST_PORT..=POST_PORT, "post");
+}
+
+/// Init milestones in the order `kernel_main` reaches them.
+///
+/// The numbering is stable so a code read off port 0x80 can be looked up here.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Stage {
+    Gdt = 0x10,
+    Idt = 0x20,
+    Pic = 0x30,
+    Paging = 0x40,
+    Heap = 0x50,
+    Apic = 0x60,
+    Done = 0xff,
+}
+
+/// Emits the progress code of `stage` to port 0x80 and the serial port.
+///
+/// Call this *before* entering a stage, so the last code seen names the stage that hung.
+pub fn report(stage: Stage) {
+    let code = stage as u8;
+
+    unsafe {
+        let mut port = PORTS.port::<u8>(POST_PORT);
+        port.write(code);
+    }
+    serial_println!("[boot {:#04x}] {:?}", code, stage);
+}
+
+/// Waits about a microsecond by writing 0 to port 0x80, for busy waits that must not depend
+/// on a timer.
+pub fn io_delay() {
+    unsafe { PORTS.port::<u8>(POST_PORT).write(0) };
+}
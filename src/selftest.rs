@@ -0,0 +1,137 @@
+use crate::large_alloc::PAGE_SIZE;
+use crate::{interrupts, pit, progress, serial_println};
+use alloc::vec::Vec;
+use core::arch::x86_64::_rdtsc;
+
+type Check = fn() -> Result<(), &'static str>;
+
+const CHECKS: &[(&str, Check)] = &[
+    ("allocator stress", allocator_stress),
+    ("page mapping round trip", page_mapping_round_trip),
+    ("interrupt self-test", interrupt_self_test),
+    ("timer accuracy", timer_accuracy),
+];
+
+/// Rate the timer accuracy check programs the PIT to.
+const TIMER_TEST_HZ: u32 = 100;
+const TIMER_TEST_TICKS: usize = 10;
+/// Largest deviation of one tick interval from the mean, and of the mean from the interval
+/// `TIMER_TEST_HZ` asks for, in percent.
+const TIMER_TOLERANCE_PERCENT: u64 = 10;
+/// About 100 ms of POST port writes, ten ticks at `TIMER_TEST_HZ`.
+const TICK_WAIT_ITERATIONS: usize = 100_000;
+
+/// Runs the in-kernel checks and reports each result and a summary over serial.
+///
+/// Needs interrupts enabled and the heap, including large allocations, set up. Returns
+/// whether every check passed.
+pub fn run() -> bool {
+    let mut failed = 0;
+    for &(name, check) in CHECKS {
+        match check() {
+            Ok(()) => serial_println!("selftest: {}... [ok]", name),
+            Err(reason) => {
+                serial_println!("selftest: {}... [failed] {}", name, reason);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed == 0 {
+        serial_println!("selftest: PASS ({} checks)", CHECKS.len());
+    } else {
+        serial_println!("selftest: FAIL ({} of {} checks failed)", failed, CHECKS.len());
+    }
+    failed == 0
+}
+
+/// Interleaves allocations and frees of odd sizes, then checks no buffer was overwritten.
+fn allocator_stress() -> Result<(), &'static str> {
+    let mut buffers: Vec<Vec<u8>> = Vec::new();
+    for i in 0..64usize {
+        buffers.push(vec![i as u8; 1 + i * 37 % 512]);
+        if i % 3 == 0 {
+            buffers.remove(0);
+        }
+    }
+
+    for buffer in &buffers {
+        if buffer.iter().any(|&byte| byte != buffer[0]) {
+            return Err("heap buffer corrupted");
+        }
+    }
+    Ok(())
+}
+
+/// Allocations above a page are backed by freshly mapped pages, so this maps, writes, reads
+/// back and unmaps them twice over.
+fn page_mapping_round_trip() -> Result<(), &'static str> {
+    for round in 0..2u8 {
+        let mut pages: Vec<u8> = Vec::with_capacity(3 * PAGE_SIZE);
+        for i in 0..3 * PAGE_SIZE {
+            pages.push((i as u8).wrapping_add(round));
+        }
+        if pages.iter().enumerate().any(|(i, &byte)| byte != (i as u8).wrapping_add(round)) {
+            return Err("mapped pages did not read back what was written");
+        }
+    }
+    Ok(())
+}
+
+fn interrupt_self_test() -> Result<(), &'static str> {
+    interrupts::self_test().map_err(|err| match err {
+        interrupts::SelfTestError::TimerSilent => "no timer interrupt",
+        interrupts::SelfTestError::NoKeyboardController => "no keyboard controller",
+    })
+}
+
+/// Checks that the timer interrupt fires at `TIMER_TEST_HZ` and at an even rate, timed with
+/// the TSC, which is calibrated against the PIT's input clock first.
+fn timer_accuracy() -> Result<(), &'static str> {
+    let tsc_frequency = pit::tsc_frequency().ok_or("PIT channel 2 did not count down")?;
+    pit::set_frequency(TIMER_TEST_HZ);
+    let result = measure_tick_intervals();
+    pit::reset();
+
+    let intervals = result?;
+    let mean = intervals.iter().sum::<u64>() / intervals.len() as u64;
+    let expected = tsc_frequency / u64::from(TIMER_TEST_HZ);
+    let tolerance = expected * TIMER_TOLERANCE_PERCENT / 100;
+    if mean < expected - tolerance || mean > expected + tolerance {
+        return Err("timer rate is more than 10% off TIMER_TEST_HZ");
+    }
+    let tolerance = mean * TIMER_TOLERANCE_PERCENT / 100;
+    if intervals
+        .iter()
+        .any(|&interval| interval < mean - tolerance || interval > mean + tolerance)
+    {
+        return Err("tick intervals vary by more than 10%");
+    }
+    Ok(())
+}
+
+fn measure_tick_intervals() -> Result<[u64; TIMER_TEST_TICKS], &'static str> {
+    // the first tick after reprogramming may come early
+    wait_for_tick()?;
+    let mut last = wait_for_tick()?;
+
+    let mut intervals = [0; TIMER_TEST_TICKS];
+    for interval in intervals.iter_mut() {
+        let now = wait_for_tick()?;
+        *interval = now - last;
+        last = now;
+    }
+    Ok(intervals)
+}
+
+/// Waits for the next timer interrupt and returns the TSC right after it.
+fn wait_for_tick() -> Result<u64, &'static str> {
+    let start = interrupts::ticks();
+    for _ in 0..TICK_WAIT_ITERATIONS {
+        if interrupts::ticks() != start {
+            return Ok(unsafe { _rdtsc() } as u64);
+        }
+        progress::io_delay();
+    }
+    Err("timer interrupt did not arrive")
+}
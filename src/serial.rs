@@ -1,13 +1,166 @@
+use core::fmt;
 use lazy_static::lazy_static;
 use spin::Mutex;
+use crate::portio::PortRange;
 use uart_16550::SerialPort;
+use x86_64::instructions::port::Port;
+
+const COM1: u16 = 0x3F8;
+
+const XON: u8 = 0x11;
+const XOFF: u8 = 0x13;
+
+// offsets from the port base
+const DATA: u16 = 0;
+const LINE_STATUS: u16 = 5;
+const MODEM_STATUS: u16 = 6;
+/// Keeps whatever is written to it, which tells a UART from an empty bus.
+const SCRATCH: u16 = 7;
+
+const LINE_STATUS_DATA_READY: u8 = 1;
+const MODEM_STATUS_CTS: u8 = 1 << 4;
+
+/// Polls for CTS or XON before a byte is sent anyway and flow control is turned off, about
+/// a second. Bytes are sent with interrupts off, so a host that never lets go must not hang
+/// the kernel.
+const FLOW_CONTROL_POLLS: usize = 1_000_000;
 
 lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = SerialPort::new(0x3F8);
-        serial_port.init();
-        Mutex::new(serial_port)
-    };
+    pub static ref SERIAL1: Mutex<Serial> = Mutex::new(Serial::new(COM1));
+}
+
+crate::kernel_driver!(SERIAL_DRIVER, "serial", check_ports);
+
+// the ports are claimed by `Serial::new` on the first print, which may come before this
+fn check_ports() -> Result<(), &'static str> {
+    use x86_64::instructions::interrupts;
+
+    if interrupts::without_interrupts(|| SERIAL1.lock().ports_conflict) {
+        Err("ports already claimed by another driver")
+    } else {
+        Ok(())
+    }
+}
+
+/// How the serial port lets the host throttle our output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlowControl {
+    /// Send as fast as the UART allows.
+    None,
+    /// Only send while the host asserts CTS.
+    RtsCts,
+    /// Stop sending after XOFF from the host, resume after XON.
+    XonXoff,
+}
+
+/// A UART with optional flow control on transmit.
+pub struct Serial {
+    port: SerialPort,
+    base: u16,
+    ports: PortRange,
+    /// Whether another driver had claimed some of `ports` first.
+    ports_conflict: bool,
+    flow_control: FlowControl,
+    paused: bool,
+}
+
+impl Serial {
+    fn new(base: u16) -> Serial {
+        // `PortRange::claim` would report a conflict over this very port
+        let (ports, conflict) = PortRange::claim_unreported(base..=base + SCRATCH, "serial");
+        // does its own port I/O, within the claim
+        let mut port = SerialPort::new(base);
+        // also asserts RTS, telling the host we are ready to receive
+        port.init();
+
+        Serial {
+            port,
+            base,
+            ports,
+            ports_conflict: conflict.is_some(),
+            flow_control: FlowControl::None,
+            paused: false,
+        }
+    }
+
+    pub fn flow_control(&self) -> FlowControl {
+        self.flow_control
+    }
+
+    pub fn set_flow_control(&mut self, flow_control: FlowControl) {
+        self.flow_control = flow_control;
+        self.paused = false;
+    }
+
+    /// Returns whether a UART answers at the port base: the scratch register reads back
+    /// what was written to it.
+    fn is_present(&mut self) -> bool {
+        let mut scratch = self.register(SCRATCH);
+        unsafe {
+            scratch.write(0x5a);
+            scratch.read() == 0x5a
+        }
+    }
+
+    fn send(&mut self, byte: u8) {
+        let ready = match self.flow_control {
+            FlowControl::None => true,
+            FlowControl::RtsCts => (0..FLOW_CONTROL_POLLS)
+                .any(|_| unsafe { self.register(MODEM_STATUS).read() } & MODEM_STATUS_CTS != 0),
+            FlowControl::XonXoff => (0..FLOW_CONTROL_POLLS).any(|_| {
+                self.poll_xon_xoff();
+                !self.paused
+            }),
+        };
+        if !ready {
+            self.set_flow_control(FlowControl::None);
+        }
+        self.port.send(byte);
+    }
+
+    /// Consumes received bytes, looking for XON and XOFF. There is no receive path yet, so
+    /// other bytes are dropped.
+    fn poll_xon_xoff(&mut self) {
+        let line_status = self.register(LINE_STATUS);
+        let data = self.register(DATA);
+        while unsafe { line_status.read() } & LINE_STATUS_DATA_READY != 0 {
+            match unsafe { data.read() } {
+                XOFF => self.paused = true,
+                XON => self.paused = false,
+                _ => {}
+            }
+        }
+    }
+
+    fn register(&self, offset: u16) -> Port<u8> {
+        self.ports.port(self.base + offset)
+    }
+}
+
+impl fmt::Write for Serial {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
+
+/// Sets the flow control policy of the serial console.
+///
+/// If the host holds output back for about a second, the console falls back to
+/// `FlowControl::None` until this is called again.
+pub fn set_flow_control(flow_control: FlowControl) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| SERIAL1.lock().set_flow_control(flow_control));
+}
+
+/// Returns whether there is a UART behind the serial console.
+pub fn is_present() -> bool {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| SERIAL1.lock().is_present())
 }
 
 #[doc(hidden)]
@@ -0,0 +1,42 @@
+use crate::apic::{self, IpiDestination};
+use x86_64::instructions::tlb;
+
+/// Asks the receiving CPU to flush its TLB.
+pub const TLB_SHOOTDOWN_VECTOR: u8 = 0xfc;
+/// Asks the receiving CPU to run its scheduler.
+pub const RESCHEDULE_VECTOR: u8 = 0xfd;
+/// Freezes the receiving CPU because another CPU panicked.
+pub const PANIC_STOP_VECTOR: u8 = 0xfe;
+
+/// Returns the local APIC id of the current CPU.
+pub fn current_cpu() -> u8 {
+    apic::id()
+}
+
+/// Sends the interrupt `vector` to the CPU with the local APIC id `cpu`.
+pub fn send_ipi(cpu: u8, vector: u8) {
+    apic::send_ipi(IpiDestination::Cpu(cpu), vector)
+}
+
+/// Sends the interrupt `vector` to every other CPU.
+pub fn broadcast_ipi(vector: u8) {
+    apic::send_ipi(IpiDestination::AllButSelf, vector)
+}
+
+/// Flushes the TLB of the current CPU and makes every other CPU do the same.
+///
+/// Call this after changing a mapping that other CPUs may have cached.
+pub fn tlb_shootdown() {
+    tlb::flush_all();
+    broadcast_ipi(TLB_SHOOTDOWN_VECTOR);
+}
+
+/// Asks the CPU `cpu` to reschedule.
+pub fn reschedule(cpu: u8) {
+    send_ipi(cpu, RESCHEDULE_VECTOR)
+}
+
+/// Freezes every other CPU.
+pub fn stop_other_cpus() {
+    broadcast_ipi(PANIC_STOP_VECTOR)
+}
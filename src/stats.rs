@@ -0,0 +1,156 @@
+use crate::println;
+use core::mem;
+use core::sync::atomic::{AtomicIsize, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+const MAX_STATS: usize = 64;
+const HISTOGRAM_BUCKETS: usize = 16;
+
+/// A count that only goes up, like the number of interrupts.
+pub struct Counter {
+    name: &'static str,
+    value: AtomicUsize,
+}
+
+impl Counter {
+    pub const fn new(name: &'static str) -> Counter {
+        Counter {
+            name,
+            value: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, amount: usize) {
+        self.value.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> usize {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+/// A current level that goes up and down, like bytes in use.
+pub struct Gauge {
+    name: &'static str,
+    value: AtomicIsize,
+}
+
+impl Gauge {
+    pub const fn new(name: &'static str) -> Gauge {
+        Gauge {
+            name,
+            value: AtomicIsize::new(0),
+        }
+    }
+
+    pub fn set(&self, value: isize) {
+        self.value.store(value, Ordering::Relaxed);
+    }
+
+    pub fn add(&self, amount: isize) {
+        self.value.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> isize {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+/// A distribution of values in power of two buckets: bucket 0 counts zeros, bucket `i`
+/// counts values in `[2^(i-1), 2^i)`, and the last bucket everything above.
+pub struct Histogram {
+    name: &'static str,
+    buckets: [AtomicUsize; HISTOGRAM_BUCKETS],
+}
+
+impl Histogram {
+    pub const fn new(name: &'static str) -> Histogram {
+        Histogram {
+            name,
+            buckets: [
+                AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+                AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+                AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+                AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+            ],
+        }
+    }
+
+    pub fn record(&self, value: usize) {
+        let bits = (mem::size_of::<usize>() * 8) as u32;
+        let bucket = (bits - value.leading_zeros()) as usize;
+        self.buckets[bucket.min(HISTOGRAM_BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the number of values recorded in each bucket.
+    pub fn buckets(&self) -> [usize; HISTOGRAM_BUCKETS] {
+        let mut buckets = [0; HISTOGRAM_BUCKETS];
+        for (count, bucket) in buckets.iter_mut().zip(self.buckets.iter()) {
+            *count = bucket.load(Ordering::Relaxed);
+        }
+        buckets
+    }
+}
+
+/// A registered statistic.
+#[derive(Clone, Copy)]
+pub enum Stat {
+    Counter(&'static Counter),
+    Gauge(&'static Gauge),
+    Histogram(&'static Histogram),
+}
+
+impl Stat {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Stat::Counter(counter) => counter.name,
+            Stat::Gauge(gauge) => gauge.name,
+            Stat::Histogram(histogram) => histogram.name,
+        }
+    }
+
+    fn address(&self) -> usize {
+        match *self {
+            Stat::Counter(counter) => counter as *const _ as usize,
+            Stat::Gauge(gauge) => gauge as *const _ as usize,
+            Stat::Histogram(histogram) => histogram as *const _ as usize,
+        }
+    }
+}
+
+static REGISTRY: Mutex<[Option<Stat>; MAX_STATS]> = Mutex::new([None; MAX_STATS]);
+
+/// Adds `stat` to the ones printed by `dump`. Registering a statistic twice has no effect.
+///
+/// Updating a statistic never takes the registry lock, so it is fine in interrupt handlers.
+pub fn register(stat: Stat) {
+    without_interrupts(|| {
+        let mut registry = REGISTRY.lock();
+        if registry.iter().filter_map(|s| *s).any(|s| s.address() == stat.address()) {
+            return;
+        }
+        match registry.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => *slot = Some(stat),
+            None => panic!("too many statistics, {} does not fit", stat.name()),
+        }
+    });
+}
+
+/// Prints every registered statistic, in registration order.
+pub fn dump() {
+    let registry = without_interrupts(|| *REGISTRY.lock());
+    for stat in registry.iter().filter_map(|stat| *stat) {
+        match stat {
+            Stat::Counter(counter) => println!("{}: {}", counter.name, counter.get()),
+            Stat::Gauge(gauge) => println!("{}: {}", gauge.name, gauge.get()),
+            Stat::Histogram(histogram) => {
+                println!("{}: {:?}", histogram.name, histogram.buckets())
+            }
+        }
+    }
+}
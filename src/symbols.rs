@@ -0,0 +1,167 @@
+//! Lookups between kernel addresses and function names, for backtraces, `dump_idt` and
+//! anything else that shows code addresses.
+//!
+//! The bootloader only loads the ELF segments, so `.symtab` doesn't reach memory. Instead
+//! `tools/embed-symbols.sh` copies the function symbols into the `.ksymtab` section after
+//! linking, and `init_embedded` installs them. In an image the script didn't touch, nothing
+//! resolves.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::{fmt, ptr, str};
+use spin::Once;
+
+/// Bytes reserved in `.ksymtab` for the embedded table. `tools/embed-symbols.sh` fails if
+/// the symbols don't fit.
+const EMBEDDED_SIZE: usize = 256 * 1024;
+
+/// `.ksymtab` as linked: a marker, which keeps the section from becoming `NOBITS`, followed by
+/// lines of `address size name`, in hex, up to the first NUL.
+#[repr(C)]
+struct Embedded {
+    magic: [u8; 8],
+    text: [u8; EMBEDDED_SIZE],
+}
+
+#[used]
+#[link_section = ".ksymtab"]
+static EMBEDDED: Embedded = Embedded {
+    magic: *b"ksymtab\n",
+    text: [0; EMBEDDED_SIZE],
+};
+
+/// A function or object of the kernel image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Symbol {
+    pub addr: usize,
+    /// Zero if unknown, in which case the symbol reaches up to the next one.
+    pub size: usize,
+    pub name: &'static str,
+}
+
+static TABLE: Once<&'static [Symbol]> = Once::new();
+
+/// Installs the symbol table, sorted by address. Later calls are ignored.
+pub fn init(table: &'static [Symbol]) {
+    debug_assert!(
+        table.windows(2).all(|pair| pair[0].addr <= pair[1].addr),
+        "symbol table is not sorted"
+    );
+    TABLE.call_once(|| table);
+}
+
+/// Installs the table `tools/embed-symbols.sh` wrote into the image and returns the number
+/// of symbols in it, 0 if the script didn't run. Needs the heap.
+pub fn init_embedded() -> usize {
+    // the compiler only knows the zeros the static was linked with, so read it volatile
+    let base = EMBEDDED.text.as_ptr();
+    let bytes: Vec<u8> = (0..EMBEDDED_SIZE)
+        .map(|i| unsafe { ptr::read_volatile(base.add(i)) })
+        .take_while(|&byte| byte != 0)
+        .collect();
+    let text = match str::from_utf8(Box::leak(bytes.into_boxed_slice())) {
+        Ok(text) => text,
+        Err(_) => return 0,
+    };
+    let mut table: Vec<Symbol> = text.lines().filter_map(parse_line).collect();
+    if table.is_empty() {
+        return 0;
+    }
+    table.sort_by_key(|symbol| symbol.addr);
+    let count = table.len();
+    init(Box::leak(table.into_boxed_slice()));
+    count
+}
+
+fn parse_line(line: &'static str) -> Option<Symbol> {
+    let mut fields = line.splitn(3, ' ');
+    let addr = usize::from_str_radix(fields.next()?, 16).ok()?;
+    let size = usize::from_str_radix(fields.next()?, 16).ok()?;
+    let name = fields.next()?;
+    Some(Symbol { addr, size, name })
+}
+
+fn table() -> &'static [Symbol] {
+    match TABLE.r#try() {
+        Some(table) => table,
+        None => &[],
+    }
+}
+
+/// Returns the name of the symbol containing `addr` and the offset of `addr` into it.
+pub fn resolve(addr: usize) -> Option<(&'static str, usize)> {
+    resolve_in(table(), addr)
+}
+
+/// Returns the address of the symbol called `name`.
+pub fn lookup(name: &str) -> Option<usize> {
+    table().iter().find(|symbol| symbol.name == name).map(|symbol| symbol.addr)
+}
+
+fn resolve_in(table: &[Symbol], addr: usize) -> Option<(&'static str, usize)> {
+    // the last symbol starting at or below `addr`
+    let index = match table.binary_search_by_key(&addr, |symbol| symbol.addr) {
+        Ok(index) => index,
+        Err(0) => return None,
+        Err(index) => index - 1,
+    };
+    let symbol = table[index];
+    let offset = addr - symbol.addr;
+    if symbol.size != 0 && offset >= symbol.size {
+        return None;
+    }
+    Some((symbol.name, offset))
+}
+
+/// Formats a code address as hex, followed by `<name+offset>` if it resolves.
+#[derive(Debug, Clone, Copy)]
+pub struct Address(pub usize);
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#x}", self.0)?;
+        if let Some((name, offset)) = resolve(self.0) {
+            write!(f, " <{}+{:#x}>", name, offset)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SYMBOLS: [Symbol; 3] = [
+        Symbol { addr: 0x1000, size: 0x20, name: "kernel_main" },
+        Symbol { addr: 0x1040, size: 0, name: "idle_run" },
+        Symbol { addr: 0x2000, size: 0x10, name: "panic" },
+    ];
+
+    #[test]
+    fn resolves_addresses_inside_symbols() {
+        assert_eq!(resolve_in(&SYMBOLS, 0x1000), Some(("kernel_main", 0)));
+        assert_eq!(resolve_in(&SYMBOLS, 0x101f), Some(("kernel_main", 0x1f)));
+        assert_eq!(resolve_in(&SYMBOLS, 0x1f00), Some(("idle_run", 0xec0)));
+        assert_eq!(resolve_in(&SYMBOLS, 0x2008), Some(("panic", 8)));
+    }
+
+    #[test]
+    fn parses_embedded_lines() {
+        assert_eq!(
+            parse_line("0000000000201040 00000000000000a0 os_rust::idle::run::h0123"),
+            Some(Symbol { addr: 0x201040, size: 0xa0, name: "os_rust::idle::run::h0123" })
+        );
+        assert_eq!(
+            parse_line("201000 0 <T as core::any::Any>::type_id"),
+            Some(Symbol { addr: 0x201000, size: 0, name: "<T as core::any::Any>::type_id" })
+        );
+        assert_eq!(parse_line("201000 _start"), None);
+    }
+
+    #[test]
+    fn gaps_do_not_resolve() {
+        assert_eq!(resolve_in(&SYMBOLS, 0xfff), None);
+        assert_eq!(resolve_in(&SYMBOLS, 0x1020), None);
+        assert_eq!(resolve_in(&SYMBOLS, 0x2010), None);
+    }
+}
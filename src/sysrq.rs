@@ -0,0 +1,37 @@
+use crate::crashdump::{self, Registers};
+use crate::{heap_allocator, keyboard, println, stats, trace, HEAP_ALLOCATOR};
+
+/// Makes the PS/2 controller pulse the CPU's reset line.
+const PULSE_RESET: u8 = 0xfe;
+
+/// Runs the Alt+SysRq command bound to `key`.
+///
+/// Called straight from the keyboard interrupt, so it must work while the rest of the
+/// kernel is stuck: nothing here waits for a lock that an interrupted thread may hold.
+/// There are no tasks or filesystems yet, so unlike Linux there is nothing to dump or sync.
+pub fn handle(key: char) {
+    match key {
+        'm' => {
+            stats::dump();
+            heap_allocator::print_peaks(&HEAP_ALLOCATOR);
+        }
+        'c' => {
+            if !crashdump::try_write(&Registers::capture()) {
+                println!("SysRq: serial port busy, no crash dump");
+            }
+        }
+        't' => trace::dump(),
+        'b' => reboot(),
+        _ => println!("SysRq: m = memory stats, c = crash dump, t = trace dump, b = reboot"),
+    }
+}
+
+/// Resets the machine without syncing or shutting anything down.
+pub fn reboot() -> ! {
+    unsafe { keyboard::status_port().write(PULSE_RESET) };
+    // the controller may ignore us, so a triple fault has the last word
+    unsafe {
+        asm!("lidt ($0); int3" :: "r"(&[0u16; 5]) :: "volatile");
+    }
+    crate::idle::halt()
+}
@@ -0,0 +1,101 @@
+use crate::msr::{self, Register};
+use crate::stats::{self, Gauge, Stat};
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+// IA32_THERM_STATUS bits
+const THERM_READING_VALID: u64 = 1 << 31;
+const THERM_READING_SHIFT: u64 = 16;
+const THERM_READING_MASK: u64 = 0x7f;
+
+/// TjMax for CPUs whose MSR_TEMPERATURE_TARGET can't be read.
+const DEFAULT_TJ_MAX: u64 = 100;
+
+static TEMPERATURE: Gauge = Gauge::new("cpu.temperature_c");
+static EFFECTIVE_FREQUENCY: Gauge = Gauge::new("cpu.effective_frequency_percent");
+
+static HAS_SENSOR: AtomicBool = AtomicBool::new(false);
+static HAS_APERF: AtomicBool = AtomicBool::new(false);
+static TJ_MAX: AtomicUsize = AtomicUsize::new(DEFAULT_TJ_MAX as usize);
+// last counter values, for the deltas between two `update`s
+static LAST_APERF: AtomicUsize = AtomicUsize::new(0);
+static LAST_MPERF: AtomicUsize = AtomicUsize::new(0);
+
+/// What `init` found the CPU to report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sensors {
+    /// A digital thermal sensor, reported in the `cpu.temperature_c` gauge.
+    pub temperature: bool,
+    /// APERF/MPERF, reported in the `cpu.effective_frequency_percent` gauge as the
+    /// effective frequency in percent of the base frequency.
+    pub frequency: bool,
+}
+
+/// Looks for the thermal sensor and the APERF/MPERF counters and registers the gauges of
+/// what is there.
+///
+/// Both are Intel MSRs that QEMU's TCG does not emulate, so every MSR is probed before it
+/// is trusted, and missing ones just leave their gauge unregistered.
+pub fn init() -> Sensors {
+    let power = unsafe { __cpuid(6) };
+    // the CPUID bits are meaningless if the highest basic leaf is below 6
+    let leaf_6 = unsafe { __cpuid(0).eax } >= 6;
+
+    let temperature = leaf_6 && power.eax & 1 != 0 && msr::probe(Register::ThermStatus).is_some();
+    if temperature {
+        if let Some(target) = msr::probe(Register::TemperatureTarget) {
+            TJ_MAX.store(((target >> 16) & 0xff) as usize, Ordering::SeqCst);
+        }
+        HAS_SENSOR.store(true, Ordering::SeqCst);
+        stats::register(Stat::Gauge(&TEMPERATURE));
+    }
+
+    let frequency = leaf_6
+        && power.ecx & 1 != 0
+        && msr::probe(Register::Mperf).is_some()
+        && msr::probe(Register::Aperf).is_some();
+    if frequency {
+        HAS_APERF.store(true, Ordering::SeqCst);
+        stats::register(Stat::Gauge(&EFFECTIVE_FREQUENCY));
+    }
+
+    update();
+    Sensors {
+        temperature,
+        frequency,
+    }
+}
+
+/// Refreshes the gauges. Run periodically from the idle loop, see `interrupts`.
+pub fn update() {
+    if HAS_SENSOR.load(Ordering::SeqCst) {
+        if let Some(celsius) = temperature() {
+            TEMPERATURE.set(celsius as isize);
+        }
+    }
+
+    if HAS_APERF.load(Ordering::SeqCst) {
+        let (aperf, mperf) = unsafe { (msr::read(Register::Aperf), msr::read(Register::Mperf)) };
+        let (aperf, mperf) = (aperf as usize, mperf as usize);
+        let aperf_delta = aperf.wrapping_sub(LAST_APERF.swap(aperf, Ordering::SeqCst));
+        let mperf_delta = mperf.wrapping_sub(LAST_MPERF.swap(mperf, Ordering::SeqCst));
+        if mperf_delta != 0 {
+            EFFECTIVE_FREQUENCY.set((aperf_delta / (mperf_delta / 100).max(1)) as isize);
+        }
+    }
+}
+
+/// Reads the current core temperature in degrees Celsius, or `None` without a sensor or
+/// while its reading is invalid.
+pub fn temperature() -> Option<u64> {
+    if !HAS_SENSOR.load(Ordering::SeqCst) {
+        return None;
+    }
+    let status = unsafe { msr::read(Register::ThermStatus) };
+    if status & THERM_READING_VALID == 0 {
+        return None;
+    }
+    // the sensor reports how far below TjMax the core is
+    let below = (status >> THERM_READING_SHIFT) & THERM_READING_MASK;
+    Some((TJ_MAX.load(Ordering::SeqCst) as u64).saturating_sub(below))
+}
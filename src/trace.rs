@@ -0,0 +1,137 @@
+//! Tracepoints written into per-CPU ring buffers, for timelines of what the kernel did.
+//!
+//! Interrupt entry and exit and allocations above the `trace.alloc_threshold` knob are
+//! traced. There is no scheduler yet, so there are no task switches or wakeups to trace.
+
+use crate::{config, serial_print, serial_println, smp};
+use core::arch::x86_64::_rdtsc;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// CPUs with a higher local APIC id are not traced.
+pub const MAX_CPUS: usize = 8;
+/// Records kept per CPU; older ones are overwritten.
+const RING_SIZE: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// An interrupt handler started, with the vector as argument.
+    IrqEnter,
+    /// An interrupt handler finished, with the vector as argument.
+    IrqExit,
+    /// A heap allocation, with its size as argument.
+    Alloc,
+}
+
+#[derive(Clone, Copy)]
+struct Record {
+    tsc: u64,
+    arg: u32,
+    event: Option<Event>,
+}
+
+struct Ring {
+    records: [Record; RING_SIZE],
+    /// Where the next record goes.
+    next: usize,
+}
+
+const EMPTY_RING: Ring = Ring {
+    records: [Record {
+        tsc: 0,
+        arg: 0,
+        event: None,
+    }; RING_SIZE],
+    next: 0,
+};
+
+// one lock per CPU, so CPUs don't contend; it only guards against `dump`
+static RINGS: [Mutex<Ring>; MAX_CPUS] = [
+    Mutex::new(EMPTY_RING),
+    Mutex::new(EMPTY_RING),
+    Mutex::new(EMPTY_RING),
+    Mutex::new(EMPTY_RING),
+    Mutex::new(EMPTY_RING),
+    Mutex::new(EMPTY_RING),
+    Mutex::new(EMPTY_RING),
+    Mutex::new(EMPTY_RING),
+];
+
+/// Appends `event` to the current CPU's ring, stamped with the TSC.
+///
+/// Safe to call from interrupt handlers and the allocator: it doesn't allocate, and drops
+/// the record if `dump` holds the ring.
+pub fn record(event: Event, arg: u32) {
+    let cpu = usize::from(smp::current_cpu());
+    if cpu >= MAX_CPUS {
+        return;
+    }
+    let tsc = unsafe { _rdtsc() };
+    without_interrupts(|| {
+        if let Some(mut ring) = RINGS[cpu].try_lock() {
+            let next = ring.next;
+            ring.records[next] = Record {
+                tsc,
+                arg,
+                event: Some(event),
+            };
+            ring.next = (next + 1) % RING_SIZE;
+        }
+    });
+}
+
+pub fn irq_enter(vector: u8) {
+    record(Event::IrqEnter, u32::from(vector));
+}
+
+pub fn irq_exit(vector: u8) {
+    record(Event::IrqExit, u32::from(vector));
+}
+
+/// Traces an allocation of `size` bytes if it is above the `trace.alloc_threshold` knob.
+pub fn alloc(size: usize) {
+    if size > config::TRACE_ALLOC_THRESHOLD.get() {
+        record(Event::Alloc, size.min(u32::max_value() as usize) as u32);
+    }
+}
+
+/// Prints every CPU's records to the serial port in the JSON format of Chrome's trace
+/// viewer (chrome://tracing), one thread per CPU, oldest record first.
+///
+/// The TSC isn't calibrated, so timestamps are in TSC cycles: the viewer shows each cycle
+/// as a microsecond.
+pub fn dump() {
+    serial_println!("{{\"otherData\": {{\"clock\": \"tsc\"}}, \"traceEvents\": [");
+    let mut first = true;
+    for cpu in 0..MAX_CPUS {
+        let (records, next) = without_interrupts(|| {
+            let ring = RINGS[cpu].lock();
+            (ring.records, ring.next)
+        });
+        for i in 0..RING_SIZE {
+            let record = records[(next + i) % RING_SIZE];
+            let event = match record.event {
+                Some(event) => event,
+                None => continue,
+            };
+            if !first {
+                serial_println!(",");
+            }
+            first = false;
+            print_event(cpu, event, record);
+        }
+    }
+    serial_println!("]}}");
+}
+
+fn print_event(cpu: usize, event: Event, record: Record) {
+    serial_print!("{{\"pid\": 0, \"tid\": {}, \"ts\": {}, ", cpu, record.tsc);
+    match event {
+        Event::IrqEnter => serial_print!("\"ph\": \"B\", \"name\": \"irq {:#x}\"}}", record.arg),
+        Event::IrqExit => serial_print!("\"ph\": \"E\", \"name\": \"irq {:#x}\"}}", record.arg),
+        Event::Alloc => serial_print!(
+            "\"ph\": \"i\", \"s\": \"t\", \"name\": \"alloc\", \"args\": {{\"size\": {}}}}}",
+            record.arg
+        ),
+    }
+}
@@ -2,15 +2,29 @@ use core::fmt;
 use lazy_static::lazy_static;
 use spin::Mutex;
 use volatile::Volatile;
+use x86_64::structures::paging::{MapToError, PageTableFlags, PhysFrame};
+use x86_64::PhysAddr;
+
+/// Physical address of the text mode buffer, which the writer accesses identity mapped.
+const BUFFER_ADDR: usize = 0xb8000;
 
 lazy_static! {
     pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
         column_position: 0,
         color_code: ColorCode::new(Color::Green, Color::Black),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+        buffer: unsafe { &mut *(BUFFER_ADDR as *mut Buffer) },
     });
 }
 
+/// Reserves and identity maps the buffer, so the writer no longer depends on the
+/// bootloader's identity map. Must run after `memory::init_kernel_pages`.
+pub fn map_buffer() -> Result<(), MapToError> {
+    let start = PhysAddr::new(BUFFER_ADDR as u64);
+    crate::memory::reserve(start..start + core::mem::size_of::<Buffer>() as u64, "vga");
+    let frame = PhysFrame::containing_address(PhysAddr::new(BUFFER_ADDR as u64));
+    crate::memory::identity_map(frame, PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE)
+}
+
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
@@ -95,6 +109,34 @@ impl Writer {
         }
     }
 
+    /// Blanks the character before the cursor and moves the cursor onto it. Stops at the
+    /// start of the line.
+    pub fn backspace(&mut self) {
+        if self.column_position == 0 {
+            return;
+        }
+        self.column_position -= 1;
+
+        let color_code = self.color_code;
+        self.buffer.chars[BUFFER_HEIGHT - 1][self.column_position].write(ScreenChar {
+            ascii_character: b' ',
+            color_code,
+        });
+    }
+
+    /// Changes the colors used for subsequent output.
+    pub fn set_color(&mut self, foreground: Color, background: Color) {
+        self.color_code = ColorCode::new(foreground, background);
+    }
+
+    /// Blanks the whole screen with the current colors.
+    pub fn clear_screen(&mut self) {
+        for row in 0..BUFFER_HEIGHT {
+            self.clear_row(row);
+        }
+        self.column_position = 0;
+    }
+
     fn new_line(&mut self) {
         for row in 1..BUFFER_HEIGHT {
             for col in 0..BUFFER_WIDTH {
@@ -0,0 +1,46 @@
+#!/bin/sh
+# Copies the function symbols of a linked kernel into its `.ksymtab` section, where
+# `symbols::init_embedded` finds them, so backtraces and `dump_idt` show names. Run it on
+# the kernel ELF before it is packed into a boot image:
+#
+#     cargo xbuild
+#     tools/embed-symbols.sh target/x86_64-os_rust/debug/os_rust
+#     bootimage build
+#
+# The section keeps its size, so nothing in the kernel moves. NM and OBJCOPY override the
+# tools used; llvm-nm and llvm-objcopy work as well as the binutils ones.
+set -eu
+
+if [ $# -ne 1 ]; then
+    echo "usage: $0 <kernel elf>" >&2
+    exit 1
+fi
+kernel=$1
+nm=${NM:-nm}
+objcopy=${OBJCOPY:-objcopy}
+
+tmp=$(mktemp -d)
+trap 'rm -rf "$tmp"' EXIT
+
+"$objcopy" --dump-section .ksymtab="$tmp/linked" "$kernel" "$tmp/unused"
+size=$(wc -c < "$tmp/linked")
+
+# the marker `symbols::EMBEDDED` starts with, then `address size name` in hex, with size 0
+# where nm doesn't know it
+printf 'ksymtab\n' > "$tmp/table"
+"$nm" --defined-only --numeric-sort --print-size --demangle "$kernel" | awk '
+    $2 ~ /^[tTwW]$/ { name = $0; sub(/^[^ ]+ [^ ]+ /, "", name); print $1, 0, name; next }
+    $3 ~ /^[tTwW]$/ { name = $0; sub(/^[^ ]+ [^ ]+ [^ ]+ /, "", name); print $1, $2, name }
+' >> "$tmp/table"
+
+used=$(wc -c < "$tmp/table")
+# keep at least one NUL, which ends the table
+if [ "$used" -ge "$size" ]; then
+    echo "$0: $used bytes of symbols don't fit into .ksymtab ($size bytes)," \
+        "raise EMBEDDED_SIZE in src/symbols.rs" >&2
+    exit 1
+fi
+head -c $((size - used)) /dev/zero >> "$tmp/table"
+
+"$objcopy" --update-section .ksymtab="$tmp/table" "$kernel"
+echo "embedded $(($(wc -l < "$tmp/table") - 1)) symbols, $used of $size bytes"